@@ -0,0 +1,396 @@
+//! Delta uploads of files that changed little since their last upload.
+//!
+//! Next to each file it uploads, davsync stores the rsync signature of its
+//! content in a hidden `.<name>.davsync-sig` file. When the file changes, the
+//! signature of the version on the server tells which blocks of the new
+//! content are already there: the server copies the old version to a
+//! temporary file, the other bytes are written into it with `Content-Range`
+//! PUTs and it is moved into place. Appending to a large file thus only sends
+//! what was appended.
+//!
+//! A signature is trusted as long as the size, modification time and entity
+//! tag it records are the ones of the file on the server. Otherwise, or when
+//! the server does not take `Content-Range`, the whole file is uploaded. Only
+//! delta uploads hold the file in memory: the others stream it, computing its
+//! signature on the way.
+//!
+//! Signatures follow their file when it is renamed or deleted. The ones whose
+//! file is gone anyway are found by walks and removed after the run.
+
+use std::io::{self, Cursor, Read};
+use std::sync::{Arc, Mutex};
+
+use fast_rsync::{Signature, SignatureOptions};
+use rustydav::prelude::Url;
+
+use super::upload::{hidden, visible};
+use super::webdav::WebDavBackend;
+use super::Entry;
+use crate::compare::seconds;
+use crate::dav::Resource;
+use crate::error::{Error, Result};
+
+/// First line of the signature files.
+const HEADER: &str = "davsync-sig 1";
+
+const SIGNATURE_OPTIONS: SignatureOptions = SignatureOptions {
+    block_size: 8192,
+    crypto_hash_size: 8,
+};
+
+/// Number of bytes signed at once when streaming a file, a multiple of the
+/// block size.
+const SIGNED_SPAN: usize = 128 * SIGNATURE_OPTIONS.block_size as usize;
+
+/// Path of the file the signature at `path` belongs to, if it is one.
+pub(super) fn signed_file(path: &str) -> Option<String> {
+    visible(path, "davsync-sig")
+}
+
+/// Magic number starting the deltas in the librsync format.
+const DELTA_MAGIC: [u8; 4] = [0x72, 0x73, 0x02, 0x36];
+
+impl WebDavBackend {
+    /// Uploads `entry`, whose content is `data`, to `url`, sending only the
+    /// bytes the version on the server lacks when its signature is known.
+    /// Then stores the signature of the new content.
+    pub(super) fn upload_delta(
+        &self,
+        entry: &Entry,
+        url: &Url,
+        mut data: Box<dyn Read + Send>,
+    ) -> Result<()> {
+        let signature_url = self.signature_url(&entry.path);
+        let signature = match self.remote_signature(url, &signature_url)? {
+            // Ranges cannot make a file shorter.
+            Some((old, size)) if size <= entry.size => {
+                let mut content = Vec::new();
+                data.read_to_end(&mut content)?;
+                let signature = Signature::calculate(&content, SIGNATURE_OPTIONS);
+                if !self.patch(entry, url, &old, &content)? {
+                    self.upload(entry, url, Box::new(Cursor::new(content)))?;
+                }
+                Some(signature.into_serialized())
+            }
+            _ => {
+                let signer = Arc::new(Mutex::new(Signer::default()));
+                let signing = Signing {
+                    data,
+                    signer: Arc::clone(&signer),
+                };
+                self.upload(entry, url, Box::new(signing))?;
+                let mut signer = signer.lock().expect("no upload panicked");
+                signer.finish(entry.size)
+            }
+        };
+
+        // The signature describes the file as the server now has it.
+        if let (Some(signature), Some(resource)) = (signature, self.client.stat(url)?) {
+            let mut body = format!("{}\n{}\n", HEADER, version(&resource)).into_bytes();
+            body.extend_from_slice(&signature);
+            self.client.put(&signature_url, body.into(), None, None)?;
+        }
+        Ok(())
+    }
+
+    /// URL of the signature of the file at `path`.
+    pub(super) fn signature_url(&self, path: &str) -> Url {
+        self.url(&hidden(path, "davsync-sig"), false)
+    }
+
+    /// Moves the signature of the file at `from` along with it to `to`.
+    pub(super) fn rename_signature(&self, from: &str, to: &str) {
+        let signature_url = self.signature_url(to);
+        if self
+            .client
+            .mv(&self.signature_url(from), &signature_url)
+            .is_err()
+        {
+            // Without a signature to move, the one at `to` describes the
+            // replaced file.
+            let _ = self.client.delete(&signature_url);
+        }
+    }
+
+    /// Tells whether the hidden file at `path` is the signature of a file
+    /// that no longer exists.
+    pub(super) fn is_orphan_signature(&self, path: &str) -> bool {
+        signed_file(path).is_some_and(|file| {
+            self.client
+                .stat(&self.url(&file, false))
+                .is_ok_and(|resource| resource.is_none_or(|resource| resource.is_collection))
+        })
+    }
+
+    /// Returns the signature stored for the file at `url` and its size, when
+    /// it still describes the file.
+    fn remote_signature(&self, url: &Url, signature_url: &Url) -> Result<Option<(Signature, u64)>> {
+        let resource = match self.client.stat(url)? {
+            Some(resource) if !resource.is_collection => resource,
+            _ => return Ok(None),
+        };
+        let mut stored = Vec::new();
+        match self.client.get(signature_url) {
+            Ok(mut response) => response.read_to_end(&mut stored)?,
+            Err(Error::Status { status: 404, .. }) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let expected = format!("{}\n{}\n", HEADER, version(&resource));
+        if !stored.starts_with(expected.as_bytes()) {
+            return Ok(None);
+        }
+        let signature = Signature::deserialize(stored.split_off(expected.len())).ok();
+        Ok(signature.map(|signature| (signature, resource.size)))
+    }
+
+    /// Turns the file at `url`, whose signature is `old`, into `content` by
+    /// writing the bytes that differ. Returns `false` when the server cannot
+    /// do that.
+    fn patch(&self, entry: &Entry, url: &Url, old: &Signature, content: &[u8]) -> Result<bool> {
+        if !self.takes_partial_put(&entry.path)? {
+            return Ok(false);
+        }
+        let mut delta = Vec::new();
+        if fast_rsync::diff(&old.index(), content, &mut delta).is_err() {
+            return Ok(false);
+        }
+        let ranges = match changed_ranges(&delta, content.len()) {
+            Some(ranges) => ranges,
+            None => return Ok(false),
+        };
+
+        let temporary = if self.settings.inplace {
+            url.clone()
+        } else {
+            let temporary = self.url(&hidden(&entry.path, "davsync-upload"), false);
+            self.client.copy(url, &temporary)?;
+            temporary
+        };
+        let total = content.len() as u64;
+        let chunk_size = self.settings.chunk_size as usize;
+        for (start, end) in ranges {
+            for offset in (start..end).step_by(chunk_size) {
+                let chunk = content[offset..end.min(offset + chunk_size)].to_vec();
+                self.client
+                    .put_range(&temporary, offset as u64, chunk, total)?;
+            }
+        }
+        if temporary != *url {
+            self.client.mv(&temporary, url)?;
+        }
+        if let Some(modified) = entry.modified {
+            self.set_modified(url, modified)?;
+        }
+        Ok(true)
+    }
+}
+
+/// Computes a signature from the successive parts of a file, holding at
+/// most [`SIGNED_SPAN`] bytes of it.
+///
+/// Blocks are signed independently, so the signatures of block-aligned parts
+/// put end to end, without their header, are the signature of the whole file.
+#[derive(Default)]
+struct Signer {
+    pending: Vec<u8>,
+    serialized: Vec<u8>,
+    len: u64,
+}
+
+impl Signer {
+    fn update(&mut self, data: &[u8]) {
+        self.len += data.len() as u64;
+        self.pending.extend_from_slice(data);
+        while self.pending.len() >= SIGNED_SPAN {
+            let rest = self.pending.split_off(SIGNED_SPAN);
+            let span = std::mem::replace(&mut self.pending, rest);
+            self.sign(&span);
+        }
+    }
+
+    fn sign(&mut self, span: &[u8]) {
+        let signature = Signature::calculate(span, SIGNATURE_OPTIONS);
+        let serialized = signature.serialized();
+        if self.serialized.is_empty() {
+            self.serialized.extend_from_slice(serialized);
+        } else {
+            let header = Signature::calculate(&[], SIGNATURE_OPTIONS)
+                .serialized()
+                .len();
+            self.serialized.extend_from_slice(&serialized[header..]);
+        }
+    }
+
+    /// Returns the serialized signature of the file, or `None` unless
+    /// exactly `len` bytes were signed.
+    fn finish(&mut self, len: u64) -> Option<Vec<u8>> {
+        if self.len != len {
+            return None;
+        }
+        if !self.pending.is_empty() || self.serialized.is_empty() {
+            let pending = std::mem::take(&mut self.pending);
+            self.sign(&pending);
+        }
+        Some(std::mem::take(&mut self.serialized))
+    }
+}
+
+/// Passes data through, signing it.
+struct Signing {
+    data: Box<dyn Read + Send>,
+    signer: Arc<Mutex<Signer>>,
+}
+
+impl Read for Signing {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.data.read(buf)?;
+        self.signer
+            .lock()
+            .expect("no upload panicked")
+            .update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// What the signature of a file records of its version.
+fn version(resource: &Resource) -> String {
+    format!(
+        "{} {} {}",
+        resource.size,
+        resource
+            .modified
+            .map(|modified| seconds(modified).to_string())
+            .unwrap_or_else(|| "-".to_owned()),
+        resource.etag.as_deref().unwrap_or("-")
+    )
+}
+
+/// Reads a librsync delta rebuilding a file of `len` bytes, and returns the
+/// ranges of the new file that are not already at the same place in the old
+/// one: literal data and blocks that moved.
+///
+/// Returns `None` if the delta is not valid.
+fn changed_ranges(delta: &[u8], len: usize) -> Option<Vec<(usize, usize)>> {
+    let mut delta = delta.strip_prefix(&DELTA_MAGIC)?;
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    let mut position = 0;
+    loop {
+        let (&command, rest) = delta.split_first()?;
+        delta = rest;
+        let (changed, length) = match command {
+            0x00 => break,
+            // Literal data whose length is the command itself, or follows it.
+            0x01..=0x40 => (true, command as usize),
+            0x41..=0x44 => (true, read_int(&mut delta, 1 << (command - 0x41))?),
+            // Copy from the old file, with sizes for its offset and length.
+            0x45..=0x54 => {
+                let sizes = command - 0x45;
+                let offset = read_int(&mut delta, 1 << (sizes / 4))?;
+                let length = read_int(&mut delta, 1 << (sizes % 4))?;
+                (offset != position, length)
+            }
+            _ => return None,
+        };
+        if changed && length > 0 {
+            let end = position.checked_add(length)?;
+            match ranges.last_mut() {
+                Some(last) if last.1 == position => last.1 = end,
+                _ => ranges.push((position, end)),
+            }
+            if command < 0x45 {
+                delta = delta.get(length..)?;
+            }
+        }
+        position = position.checked_add(length)?;
+    }
+    (position == len).then_some(ranges)
+}
+
+/// Reads a big-endian integer of `size` bytes.
+fn read_int(data: &mut &[u8], size: usize) -> Option<usize> {
+    let (bytes, rest) = (data.get(..size)?, data.get(size..)?);
+    *data = rest;
+    let value = bytes
+        .iter()
+        .fold(0u64, |value, &byte| value << 8 | u64::from(byte));
+    usize::try_from(value).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(commands: &[&[u8]]) -> Vec<u8> {
+        let mut delta = DELTA_MAGIC.to_vec();
+        for command in commands {
+            delta.extend_from_slice(command);
+        }
+        delta.push(0x00);
+        delta
+    }
+
+    #[test]
+    fn literal_data_is_changed() {
+        // Three bytes with the length in the command, then 300 with a
+        // two-byte length.
+        let mut long = vec![0x42, 0x01, 0x2c];
+        long.extend_from_slice(&[b'x'; 300]);
+        let delta = encode(&[&[0x03, b'a', b'b', b'c'], &long]);
+        assert_eq!(changed_ranges(&delta, 303), Some(vec![(0, 303)]));
+    }
+
+    #[test]
+    fn blocks_copied_in_place_are_unchanged() {
+        // Copy 8192 bytes from offset 0 (two-byte offset and length), then
+        // append 4 literal bytes.
+        let delta = encode(&[&[0x4a, 0x00, 0x00, 0x20, 0x00], &[0x04, 1, 2, 3, 4]]);
+        assert_eq!(changed_ranges(&delta, 8196), Some(vec![(8192, 8196)]));
+        let delta = encode(&[&[0x4a, 0x00, 0x00, 0x20, 0x00]]);
+        assert_eq!(changed_ranges(&delta, 8192), Some(vec![]));
+    }
+
+    #[test]
+    fn moved_blocks_are_changed() {
+        // Two literal bytes, then the first block of the old file, which
+        // now starts at offset 2, then old bytes at their old offset.
+        let delta = encode(&[
+            &[0x02, b'a', b'b'],
+            &[0x4a, 0x00, 0x00, 0x20, 0x00],
+            &[0x4a, 0x20, 0x02, 0x10, 0x00],
+        ]);
+        assert_eq!(changed_ranges(&delta, 12290), Some(vec![(0, 8194)]));
+    }
+
+    #[test]
+    fn invalid_deltas_are_rejected() {
+        assert_eq!(changed_ranges(&[0x00], 0), None);
+        // Truncated literal data.
+        assert_eq!(changed_ranges(&encode(&[&[0x04, 1, 2]]), 4), None);
+        // Wrong length for the new file.
+        assert_eq!(changed_ranges(&encode(&[&[0x02, 1, 2]]), 3), None);
+    }
+
+    #[test]
+    fn signatures_of_streamed_files_are_the_whole_ones() {
+        let content: Vec<u8> = (0..3 * SIGNED_SPAN + 100)
+            .map(|i| (i * 7 % 251) as u8)
+            .collect();
+        let mut signer = Signer::default();
+        for part in content.chunks(10_000) {
+            signer.update(part);
+        }
+        let whole = Signature::calculate(&content, SIGNATURE_OPTIONS);
+        assert_eq!(
+            signer.finish(content.len() as u64).as_deref(),
+            Some(whole.serialized())
+        );
+
+        let mut signer = Signer::default();
+        let empty = Signature::calculate(&[], SIGNATURE_OPTIONS);
+        assert_eq!(signer.finish(0).as_deref(), Some(empty.serialized()));
+
+        let mut signer = Signer::default();
+        signer.update(b"short");
+        assert_eq!(signer.finish(10), None);
+    }
+}
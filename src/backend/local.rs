@@ -0,0 +1,424 @@
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{self, Path, PathBuf};
+
+use cli_toolbox::reportln;
+
+use crate::backend::{is_temporary, join, Backend, Entry, Partial};
+use crate::compare;
+use crate::error::{AtPath, Error, Result};
+use crate::state::state_dir;
+
+/// A directory or file on the local filesystem.
+pub struct LocalBackend {
+    root: PathBuf,
+}
+
+impl LocalBackend {
+    pub fn new(root: PathBuf) -> Self {
+        LocalBackend { root }
+    }
+
+    fn full_path(&self, path: &str) -> PathBuf {
+        if path.is_empty() {
+            self.root.clone()
+        } else {
+            self.root.join(path)
+        }
+    }
+
+    /// Identities of the directories from the root down to `path`.
+    fn ancestors(&self, path: &str) -> Result<Vec<Identity>> {
+        let mut dir = self.root.clone();
+        let mut ids = Vec::new();
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            let metadata = fs::metadata(&dir).at(&dir)?;
+            ids.push(identity(&dir, &metadata).at(&dir)?);
+            dir.push(name);
+        }
+        let metadata = fs::metadata(&dir).at(&dir)?;
+        ids.push(identity(&dir, &metadata).at(&dir)?);
+        Ok(ids)
+    }
+}
+
+impl Backend for LocalBackend {
+    fn location(&self, path: &str) -> String {
+        self.full_path(path).display().to_string()
+    }
+
+    fn stat(&self, path: &str) -> Result<Option<Entry>> {
+        let full_path = self.full_path(path);
+        match fs::metadata(&full_path) {
+            Ok(metadata) => Ok(Some(entry(path.to_owned(), &metadata))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).at(&full_path),
+        }
+    }
+
+    fn list(&self, path: &str) -> Result<Vec<Entry>> {
+        let dir = self.full_path(path);
+        let mut entries = Vec::new();
+        let mut ancestors = None;
+        for dir_entry in fs::read_dir(&dir).at(&dir)? {
+            let dir_entry = dir_entry.at(&dir)?;
+            let name = dir_entry.file_name().into_string().map_err(|name| {
+                Error::Usage(format!(
+                    "'{}' is not a valid UTF-8 name",
+                    name.to_string_lossy()
+                ))
+            })?;
+            if is_temporary(&name) {
+                continue;
+            }
+            let full_path = dir_entry.path();
+            let mut metadata = fs::symlink_metadata(&full_path).at(&full_path)?;
+            // Follow symbolic links, like the rest of the filesystem accesses,
+            // except those leading nowhere or back to a parent directory.
+            if metadata.file_type().is_symlink() {
+                metadata = match fs::metadata(&full_path) {
+                    Ok(metadata) => metadata,
+                    Err(e) => {
+                        reportln!(@err-terse "skipping '{}': {}", full_path.display(), e);
+                        continue;
+                    }
+                };
+                if metadata.is_dir() {
+                    if ancestors.is_none() {
+                        ancestors = Some(self.ancestors(path)?);
+                    }
+                    let target = identity(&full_path, &metadata).at(&full_path)?;
+                    if ancestors.as_ref().is_some_and(|ids| ids.contains(&target)) {
+                        reportln!(@err-terse "skipping '{}': symbolic link loop", full_path.display());
+                        continue;
+                    }
+                }
+            }
+            entries.push(entry(join(path, &name), &metadata));
+        }
+        Ok(entries)
+    }
+
+    fn read(&self, path: &str) -> Result<Box<dyn Read + Send>> {
+        let path = self.full_path(path);
+        Ok(Box::new(File::open(&path).at(&path)?))
+    }
+
+    fn write(&self, entry: &Entry, data: Box<dyn Read + Send>) -> Result<()> {
+        self.resume(entry, 0, data)
+    }
+
+    fn partial(&self, entry: &Entry) -> Result<Option<Partial>> {
+        let partial = partial_path(&self.full_path(&entry.path));
+        let len = match fs::metadata(&partial) {
+            Ok(metadata) => metadata.len(),
+            Err(_) => return Ok(None),
+        };
+        // Without a state directory, no entity tag could have been recorded.
+        let record = match etag_record(&partial) {
+            Ok(record) => record,
+            Err(_) => return Ok(None),
+        };
+        Ok(fs::read_to_string(record)
+            .ok()
+            .map(|etag| Partial { len, etag }))
+    }
+
+    /// Writes into a hidden `.<name>.davsync-partial` file, renamed into place
+    /// once its size, and its checksum when known, are right. An interrupted
+    /// write leaves it behind, with the entity tag of the source recorded in
+    /// the state directory to resume from there.
+    fn resume(&self, entry: &Entry, offset: u64, mut data: Box<dyn Read + Send>) -> Result<()> {
+        let path = self.full_path(&entry.path);
+        let partial = partial_path(&path);
+        // Only sources with entity tags need the state directory.
+        let record = match &entry.etag {
+            Some(_) => Some(etag_record(&partial)?),
+            None => None,
+        };
+        let mut file = if offset == 0 {
+            match (&record, &entry.etag) {
+                (Some(record), Some(etag)) => {
+                    if let Some(dir) = record.parent() {
+                        fs::create_dir_all(dir).at(dir)?;
+                    }
+                    fs::write(record, etag).at(record)?;
+                }
+                // The record of an earlier write would not describe this one.
+                _ => {
+                    if let Ok(stale) = etag_record(&partial) {
+                        remove_if_exists(&stale)?;
+                    }
+                }
+            }
+            File::create(&partial).at(&partial)?
+        } else {
+            let mut file = OpenOptions::new().write(true).open(&partial).at(&partial)?;
+            file.set_len(offset).at(&partial)?;
+            file.seek(SeekFrom::End(0)).at(&partial)?;
+            file
+        };
+        io::copy(&mut data, &mut file)?;
+
+        let len = file.metadata().at(&partial)?.len();
+        if len != entry.size {
+            if len > entry.size {
+                fs::remove_file(&partial).at(&partial)?;
+            }
+            return Err(Error::Transfer(format!(
+                "received {} bytes of '{}' instead of {}",
+                len,
+                path.display(),
+                entry.size
+            )));
+        }
+        if let Some(expected) = &entry.checksum {
+            let checksum = compare::sha1(&mut File::open(&partial).at(&partial)?).at(&partial)?;
+            if !checksum.eq_ignore_ascii_case(expected) {
+                fs::remove_file(&partial).at(&partial)?;
+                return Err(Error::Transfer(format!(
+                    "checksum mismatch for '{}': received {}, expected {}",
+                    path.display(),
+                    checksum,
+                    expected
+                )));
+            }
+        }
+        if let Some(modified) = entry.modified {
+            file.set_modified(modified).at(&partial)?;
+        }
+        drop(file);
+        fs::rename(&partial, &path).at(&path)?;
+        match record {
+            Some(record) => remove_if_exists(&record),
+            None => Ok(()),
+        }
+    }
+
+    fn mkdir(&self, path: &str) -> Result<()> {
+        let path = self.full_path(path);
+        fs::create_dir(&path).at(&path)
+    }
+
+    fn delete(&self, path: &str) -> Result<()> {
+        let full_path = self.full_path(path);
+        if fs::symlink_metadata(&full_path).at(&full_path)?.is_dir() {
+            fs::remove_dir_all(&full_path).at(&full_path)
+        } else {
+            fs::remove_file(&full_path).at(&full_path)
+        }
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let from = self.full_path(from);
+        fs::rename(&from, self.full_path(to)).at(&from)
+    }
+}
+
+/// Hidden file receiving the content of `path` until it is complete.
+fn partial_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{}.davsync-partial", name))
+}
+
+/// Location of the entity tag of the source of a partial file.
+fn etag_record(partial: &Path) -> Result<PathBuf> {
+    let partial = path::absolute(partial).at(partial)?;
+    let name = compare::sha1(&mut partial.as_os_str().as_encoded_bytes())?;
+    Ok(state_dir()?.join("downloads").join(name))
+}
+
+fn remove_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e).at(path),
+        _ => Ok(()),
+    }
+}
+
+/// What tells a directory apart from the others, whatever the links leading
+/// to it.
+#[cfg(unix)]
+type Identity = (u64, u64);
+#[cfg(not(unix))]
+type Identity = PathBuf;
+
+#[cfg(unix)]
+fn identity(_path: &Path, metadata: &Metadata) -> io::Result<Identity> {
+    use std::os::unix::fs::MetadataExt;
+    Ok((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn identity(path: &Path, _metadata: &Metadata) -> io::Result<Identity> {
+    fs::canonicalize(path)
+}
+
+fn entry(path: String, metadata: &Metadata) -> Entry {
+    Entry {
+        path,
+        is_dir: metadata.is_dir(),
+        size: if metadata.is_dir() { 0 } else { metadata.len() },
+        modified: metadata.modified().ok(),
+        etag: None,
+        checksum: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+    use std::sync::Once;
+
+    use super::*;
+
+    /// Records the entity tags of partial files in a temporary state
+    /// directory.
+    fn temp_state_dir() {
+        static ONCE: Once = Once::new();
+        ONCE.call_once(|| {
+            let dir = env::temp_dir().join(format!("davsync-{}-state", process::id()));
+            env::set_var("XDG_STATE_HOME", dir);
+        });
+    }
+
+    /// Yields its bytes, then fails like a dropped connection.
+    struct Interrupted(&'static [u8]);
+
+    impl Read for Interrupted {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_empty() {
+                return Err(io::ErrorKind::ConnectionReset.into());
+            }
+            let len = self.0.len().min(buf.len());
+            buf[..len].copy_from_slice(&self.0[..len]);
+            self.0 = &self.0[len..];
+            Ok(len)
+        }
+    }
+
+    fn file(size: u64, etag: Option<&str>) -> Entry {
+        Entry {
+            path: "file".to_owned(),
+            is_dir: false,
+            size,
+            modified: None,
+            etag: etag.map(str::to_owned),
+            checksum: None,
+        }
+    }
+
+    /// Empty directory of the test `name`.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("davsync-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn names(backend: &LocalBackend, path: &str) -> Vec<String> {
+        let mut names: Vec<String> = backend
+            .list(path)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.path)
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn broken_links_and_loops_are_skipped() {
+        use std::os::unix::fs::symlink;
+
+        let dir = temp_dir("links");
+        fs::create_dir(dir.join("sub")).unwrap();
+        fs::write(dir.join("sub/file"), "a").unwrap();
+        symlink("missing", dir.join("dangling")).unwrap();
+        symlink("sub", dir.join("link")).unwrap();
+        symlink("..", dir.join("sub/parent")).unwrap();
+        let backend = LocalBackend::new(dir.clone());
+        assert_eq!(names(&backend, ""), ["link", "sub"]);
+        assert_eq!(names(&backend, "sub"), ["sub/file"]);
+        assert_eq!(names(&backend, "link"), ["link/file"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn io_errors_name_the_file() {
+        let dir = temp_dir("errors");
+        let backend = LocalBackend::new(dir.clone());
+        let error = backend.list("missing").unwrap_err().to_string();
+        let expected = format!("'{}': ", dir.join("missing").display());
+        assert!(error.starts_with(&expected), "{}", error);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn interrupted_writes_leave_the_destination_untouched() {
+        temp_state_dir();
+        let dir = temp_dir("interrupted");
+        fs::write(dir.join("file"), "old").unwrap();
+        let backend = LocalBackend::new(dir.clone());
+        let entry = file(10, Some("\"v1\""));
+        assert!(backend
+            .write(&entry, Box::new(Interrupted(b"abcd")))
+            .is_err());
+        assert_eq!(fs::read_to_string(dir.join("file")).unwrap(), "old");
+        assert_eq!(names(&backend, ""), ["file"]);
+        let partial = backend.partial(&entry).unwrap().unwrap();
+        assert_eq!((partial.len, partial.etag.as_str()), (4, "\"v1\""));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn partials_are_resumed() {
+        temp_state_dir();
+        let dir = temp_dir("resumed");
+        let backend = LocalBackend::new(dir.clone());
+        let entry = file(10, Some("\"v1\""));
+        let _ = backend.write(&entry, Box::new(Interrupted(b"abcd")));
+        let partial = backend.partial(&entry).unwrap().unwrap();
+        backend
+            .resume(&entry, partial.len, Box::new(&b"efghij"[..]))
+            .unwrap();
+        assert_eq!(fs::read_to_string(dir.join("file")).unwrap(), "abcdefghij");
+        assert!(backend.partial(&entry).unwrap().is_none());
+        assert!(!partial_path(&dir.join("file")).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn stale_partials_are_discarded() {
+        temp_state_dir();
+        let dir = temp_dir("stale");
+        let backend = LocalBackend::new(dir.clone());
+        let _ = backend.write(&file(10, Some("\"v1\"")), Box::new(Interrupted(b"abcd")));
+
+        // The source changed: its new version is written from the start.
+        let changed = file(6, Some("\"v2\""));
+        assert_eq!(backend.partial(&changed).unwrap().unwrap().etag, "\"v1\"");
+        backend
+            .resume(&changed, 0, Box::new(&b"123456"[..]))
+            .unwrap();
+        assert_eq!(fs::read_to_string(dir.join("file")).unwrap(), "123456");
+
+        // Without an entity tag, nothing tells the bytes of an interrupted
+        // write apart from those of another version.
+        let untagged = file(10, None);
+        let _ = backend.write(&untagged, Box::new(Interrupted(b"abcd")));
+        assert!(backend.partial(&untagged).unwrap().is_none());
+
+        // Received bytes beyond the expected size are thrown away.
+        let _ = backend.write(&file(10, Some("\"v3\"")), Box::new(Interrupted(b"abcd")));
+        let shorter = file(6, Some("\"v3\""));
+        assert!(matches!(
+            backend.resume(&shorter, 4, Box::new(&b"efghij"[..])),
+            Err(Error::Transfer(_))
+        ));
+        assert!(backend.partial(&shorter).unwrap().is_none());
+        assert_eq!(fs::read_to_string(dir.join("file")).unwrap(), "123456");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,203 @@
+//! A backend keeping its tree in memory, for tests.
+
+use std::collections::BTreeMap;
+use std::io::{self, Cursor, Read};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::backend::{Backend, Entry};
+use crate::error::Result;
+
+/// A tree of directories and files held in memory, the root included.
+#[derive(Debug)]
+pub struct MemoryBackend {
+    nodes: Mutex<BTreeMap<String, Node>>,
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    /// Content of a file, `None` for a directory.
+    content: Option<Vec<u8>>,
+    modified: Option<SystemTime>,
+}
+
+impl MemoryBackend {
+    /// An empty root directory.
+    pub fn new() -> Self {
+        let mut nodes = BTreeMap::new();
+        nodes.insert(
+            String::new(),
+            Node {
+                content: None,
+                modified: None,
+            },
+        );
+        MemoryBackend {
+            nodes: Mutex::new(nodes),
+        }
+    }
+
+    /// Adds the directory at `path`, and its missing parents.
+    pub fn dir(self, path: &str) -> Self {
+        for dir in ancestors_and_self(path) {
+            self.nodes().entry(dir.to_owned()).or_insert(Node {
+                content: None,
+                modified: None,
+            });
+        }
+        self
+    }
+
+    /// Adds the file at `path`, modified `seconds` after the Unix epoch, and
+    /// its missing parent directories.
+    pub fn file(self, path: &str, content: &str, seconds: u64) -> Self {
+        let this = match path.rsplit_once('/') {
+            Some((parent, _)) => self.dir(parent),
+            None => self,
+        };
+        this.nodes().insert(
+            path.to_owned(),
+            Node {
+                content: Some(content.as_bytes().to_vec()),
+                modified: Some(UNIX_EPOCH + Duration::from_secs(seconds)),
+            },
+        );
+        this
+    }
+
+    /// Paths of all the entries, sorted, the root left out.
+    pub fn paths(&self) -> Vec<String> {
+        self.nodes()
+            .keys()
+            .filter(|path| !path.is_empty())
+            .cloned()
+            .collect()
+    }
+
+    /// Content of the file at `path`.
+    pub fn content(&self, path: &str) -> Option<String> {
+        let content = self.nodes().get(path)?.content.clone()?;
+        String::from_utf8(content).ok()
+    }
+
+    fn nodes(&self) -> std::sync::MutexGuard<'_, BTreeMap<String, Node>> {
+        self.nodes
+            .lock()
+            .expect("no test panicked while holding the tree")
+    }
+}
+
+impl Backend for MemoryBackend {
+    fn location(&self, path: &str) -> String {
+        format!("memory:/{}", path)
+    }
+
+    fn stat(&self, path: &str) -> Result<Option<Entry>> {
+        Ok(self.nodes().get(path).map(|node| entry(path, node)))
+    }
+
+    fn list(&self, path: &str) -> Result<Vec<Entry>> {
+        let nodes = self.nodes();
+        if nodes.get(path).is_none_or(|node| node.content.is_some()) {
+            return Err(not_found(path).into());
+        }
+        Ok(nodes
+            .iter()
+            .filter(|(child, _)| !child.is_empty() && parent(child) == path)
+            .map(|(child, node)| entry(child, node))
+            .collect())
+    }
+
+    fn read(&self, path: &str) -> Result<Box<dyn Read + Send>> {
+        match self.nodes().get(path).and_then(|node| node.content.clone()) {
+            Some(content) => Ok(Box::new(Cursor::new(content))),
+            None => Err(not_found(path).into()),
+        }
+    }
+
+    fn write(&self, entry: &Entry, mut data: Box<dyn Read + Send>) -> Result<()> {
+        let mut content = Vec::new();
+        data.read_to_end(&mut content)?;
+        self.nodes().insert(
+            entry.path.clone(),
+            Node {
+                content: Some(content),
+                modified: entry.modified,
+            },
+        );
+        Ok(())
+    }
+
+    fn mkdir(&self, path: &str) -> Result<()> {
+        let mut nodes = self.nodes();
+        if !nodes.contains_key(parent(path)) {
+            return Err(not_found(parent(path)).into());
+        }
+        nodes.insert(
+            path.to_owned(),
+            Node {
+                content: None,
+                modified: None,
+            },
+        );
+        Ok(())
+    }
+
+    fn delete(&self, path: &str) -> Result<()> {
+        let prefix = format!("{}/", path);
+        let mut nodes = self.nodes();
+        if nodes.remove(path).is_none() {
+            return Err(not_found(path).into());
+        }
+        nodes.retain(|child, _| !child.starts_with(&prefix));
+        Ok(())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let prefix = format!("{}/", from);
+        let mut nodes = self.nodes();
+        let moved: Vec<String> = nodes
+            .keys()
+            .filter(|path| *path == from || path.starts_with(&prefix))
+            .cloned()
+            .collect();
+        if moved.is_empty() {
+            return Err(not_found(from).into());
+        }
+        for path in moved {
+            let node = nodes.remove(&path).expect("listed above");
+            nodes.insert(format!("{}{}", to, &path[from.len()..]), node);
+        }
+        Ok(())
+    }
+}
+
+fn entry(path: &str, node: &Node) -> Entry {
+    Entry {
+        path: path.to_owned(),
+        is_dir: node.content.is_none(),
+        size: node
+            .content
+            .as_ref()
+            .map_or(0, |content| content.len() as u64),
+        modified: node.modified,
+        etag: None,
+        checksum: None,
+    }
+}
+
+fn parent(path: &str) -> &str {
+    path.rsplit_once('/').map_or("", |(parent, _)| parent)
+}
+
+/// `path` and its parent directories, the root left out.
+fn ancestors_and_self(path: &str) -> impl Iterator<Item = &str> {
+    path.char_indices()
+        .filter(|&(_, c)| c == '/')
+        .map(move |(i, _)| &path[..i])
+        .chain(Some(path).filter(|path| !path.is_empty()))
+}
+
+fn not_found(path: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("no entry at '{}'", path))
+}
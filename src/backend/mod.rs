@@ -0,0 +1,255 @@
+//! Storage backends the synchronization engine reads from and writes to.
+//!
+//! Paths given to a backend are relative to the endpoint it was opened on,
+//! use `/` as separator and never start or end with one. The empty path is
+//! the endpoint itself.
+
+mod delta;
+mod local;
+#[cfg(test)]
+mod memory;
+mod upload;
+mod webdav;
+
+use std::io::Read;
+use std::time::SystemTime;
+
+use reqwest::blocking;
+use rustydav::prelude::Url;
+
+use crate::compare;
+use crate::dav::Credentials;
+use crate::endpoint::Endpoint;
+use crate::error::Result;
+use crate::filter::Filter;
+
+pub use self::local::LocalBackend;
+#[cfg(test)]
+pub(crate) use self::memory::MemoryBackend;
+pub use self::webdav::WebDavBackend;
+
+/// A file or directory found on a backend.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    /// Path relative to the backend root.
+    pub path: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+    /// Entity tag of the file, on backends that have one.
+    pub etag: Option<String>,
+    /// SHA-1 checksum of the file, when it is already known.
+    pub checksum: Option<String>,
+}
+
+/// Operations the synchronization engine needs from a storage.
+pub trait Backend: Sync {
+    /// Human readable location of `path`, for messages.
+    fn location(&self, path: &str) -> String;
+
+    /// Returns the entry at `path`, or `None` if it does not exist.
+    fn stat(&self, path: &str) -> Result<Option<Entry>>;
+
+    /// Returns the direct children of the directory at `path`.
+    fn list(&self, path: &str) -> Result<Vec<Entry>>;
+
+    /// Returns all the entries below the directory at `path`, at any depth,
+    /// leaving out the ones `filter` excludes and the content of excluded
+    /// directories.
+    fn walk(&self, path: &str, filter: &Filter) -> Result<Vec<Entry>> {
+        let mut entries = Vec::new();
+        let mut pending = vec![path.to_owned()];
+        while let Some(dir) = pending.pop() {
+            for entry in self.list(&dir)? {
+                if filter.is_excluded(&entry.path, entry.is_dir) {
+                    continue;
+                }
+                if entry.is_dir {
+                    pending.push(entry.path.clone());
+                }
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Opens the file at `path` for reading.
+    fn read(&self, path: &str) -> Result<Box<dyn Read + Send>>;
+
+    /// Returns the SHA-1 checksum of the file `entry`.
+    ///
+    /// Uses the checksum already known for the entry if any, otherwise reads
+    /// the whole file.
+    fn checksum(&self, entry: &Entry) -> Result<String> {
+        match &entry.checksum {
+            Some(checksum) => Ok(checksum.clone()),
+            None => Ok(compare::sha1(&mut self.read(&entry.path)?)?),
+        }
+    }
+
+    /// Opens the file `entry` for reading from `offset` on, provided it still
+    /// has the entity tag `etag`.
+    ///
+    /// Returns where the data starts: `offset`, or 0 when the file changed or
+    /// the backend cannot skip its beginning.
+    fn read_from(
+        &self,
+        entry: &Entry,
+        _offset: u64,
+        _etag: &str,
+    ) -> Result<(u64, Box<dyn Read + Send>)> {
+        Ok((0, self.read(&entry.path)?))
+    }
+
+    /// Creates or replaces the file `entry` with its `size` bytes read from `data`.
+    fn write(&self, entry: &Entry, data: Box<dyn Read + Send>) -> Result<()>;
+
+    /// Returns what an interrupted write of `entry` left, which [`resume`]
+    /// can complete.
+    ///
+    /// [`resume`]: Backend::resume
+    fn partial(&self, _entry: &Entry) -> Result<Option<Partial>> {
+        Ok(None)
+    }
+
+    /// Completes the file `entry` whose first `offset` bytes were kept from
+    /// an interrupted write, with the rest read from `data`. With an `offset`
+    /// of 0, it is the same as [`write`].
+    ///
+    /// [`write`]: Backend::write
+    fn resume(&self, entry: &Entry, offset: u64, data: Box<dyn Read + Send>) -> Result<()> {
+        debug_assert_eq!(offset, 0, "only backends keeping partial files resume");
+        self.write(entry, data)
+    }
+
+    /// Creates the directory at `path`. Its parent must exist.
+    fn mkdir(&self, path: &str) -> Result<()>;
+
+    /// Deletes the file or directory at `path`, with all its content.
+    fn delete(&self, path: &str) -> Result<()>;
+
+    /// Moves the file or directory at `from` to `to`, replacing it.
+    fn rename(&self, from: &str, to: &str) -> Result<()>;
+
+    /// URL of `path` when the backend is a WebDAV server.
+    fn remote_url(&self, _path: &str) -> Option<Url> {
+        None
+    }
+
+    /// Credentials the backend logs in to its server with, for remote
+    /// backends.
+    fn credentials(&self) -> Option<&Credentials> {
+        None
+    }
+
+    /// Removes what interrupted writes left behind, as found by [`walk`],
+    /// once a synchronization succeeded.
+    ///
+    /// [`walk`]: Backend::walk
+    fn clean_up(&self) {}
+
+    /// Copies the file at `path` from `source` to the same path on this
+    /// backend without streaming it through davsync.
+    ///
+    /// Returns `false` when the two backends cannot do that, in which case
+    /// the file has to be read and written.
+    fn copy_from(&self, _source: &dyn Backend, _path: &str) -> Result<bool> {
+        Ok(false)
+    }
+}
+
+/// The beginning of a file kept from an interrupted write.
+#[derive(Debug, Clone)]
+pub struct Partial {
+    /// Number of bytes received.
+    pub len: u64,
+    /// Entity tag of the source file the bytes come from.
+    pub etag: String,
+}
+
+/// Suffixes of the hidden files davsync keeps next to the files it writes,
+/// which are never synchronized.
+const TEMPORARY_SUFFIXES: [&str; 4] = [
+    ".davsync-partial",
+    ".davsync-upload",
+    ".davsync-sig",
+    ".davsync-probe",
+];
+
+/// Tells whether `name` is one of the hidden files of davsync.
+pub(crate) fn is_temporary(name: &str) -> bool {
+    TEMPORARY_SUFFIXES
+        .iter()
+        .any(|suffix| name.starts_with('.') && name.ends_with(suffix))
+}
+
+/// Size of the chunks large files are uploaded in, unless told otherwise.
+pub const DEFAULT_CHUNK_SIZE: u64 = 10 * 1024 * 1024;
+
+/// How remote backends talk to their servers.
+#[derive(Debug, Clone)]
+pub struct Settings {
+    /// HTTP client shared by all the remote backends.
+    pub http: blocking::Client,
+    /// Number of collections listed at once when crawling.
+    pub jobs: usize,
+    /// Files larger than this are uploaded in chunks of this size, so that
+    /// an interrupted upload can be resumed.
+    pub chunk_size: u64,
+    /// Whether files are uploaded directly under their name, rather than to
+    /// a hidden temporary file moved into place.
+    pub inplace: bool,
+    /// Whether uploads only send what changed since the version on the
+    /// server, using the rsync signatures stored next to the files.
+    pub delta: bool,
+}
+
+/// Opens the backend matching `endpoint`, logging in to servers with
+/// `credentials`.
+pub fn open(
+    endpoint: &Endpoint,
+    credentials: Credentials,
+    settings: &Settings,
+) -> Box<dyn Backend> {
+    match endpoint {
+        Endpoint::Local(path) => Box::new(LocalBackend::new(path.clone())),
+        Endpoint::Remote(remote) => Box::new(WebDavBackend::new(remote, credentials, settings)),
+    }
+}
+
+/// Returns the path of `name` inside the directory `parent`.
+pub fn join(parent: &str, name: &str) -> String {
+    if parent.is_empty() {
+        name.to_owned()
+    } else {
+        format!("{}/{}", parent, name)
+    }
+}
+
+/// Iterates over the parent directories of `path`, nearest first, the root
+/// excluded.
+pub fn ancestors(path: &str) -> impl Iterator<Item = &str> {
+    path.char_indices()
+        .rev()
+        .filter(|&(_, c)| c == '/')
+        .map(move |(i, _)| &path[..i])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hidden_files_of_davsync_are_temporary() {
+        for name in [
+            ".a.txt.davsync-partial",
+            ".a.txt.davsync-upload",
+            ".a.txt.davsync-sig",
+            ".a.txt.davsync-probe",
+        ] {
+            assert!(is_temporary(name), "{}", name);
+        }
+        assert!(!is_temporary("a.txt.davsync-probe"));
+        assert!(!is_temporary(".a.txt"));
+    }
+}
@@ -0,0 +1,310 @@
+//! Resumable uploads of the files larger than the chunk size.
+//!
+//! On Nextcloud, the chunks go to an upload collection assembled into the file
+//! at the end. On servers taking `Content-Range` on PUT, they are written into
+//! a hidden `.<name>.davsync-upload` file next to the target, moved into place
+//! once complete. Other servers get a plain PUT.
+//!
+//! The progress of each upload is recorded in `uploads/` under the state
+//! directory, so that running davsync again after an interruption sends only
+//! what the server does not have yet, as long as the file did not change.
+
+use std::fs;
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use rustydav::prelude::Url;
+
+use super::webdav::WebDavBackend;
+use super::Entry;
+use crate::compare::{self, seconds};
+use crate::error::{AtPath, Error, Result};
+use crate::state::state_dir;
+
+/// Nextcloud refuses uploads of more chunks than this.
+const MAX_CHUNKS: u64 = 10000;
+
+/// An upload in progress, as recorded on disk.
+#[derive(Debug, PartialEq, Eq)]
+struct Progress {
+    size: u64,
+    modified: Option<u64>,
+    chunk_size: u64,
+    /// Upload collection or temporary file the chunks are sent to.
+    url: String,
+}
+
+impl Progress {
+    fn new(entry: &Entry, chunk_size: u64, url: &Url) -> Self {
+        Progress {
+            size: entry.size,
+            modified: entry.modified.map(seconds),
+            chunk_size,
+            url: url.to_string(),
+        }
+    }
+
+    /// Tells whether this upload is the one of `entry` as it is now.
+    fn describes(&self, entry: &Entry, chunk_size: u64) -> bool {
+        self.size == entry.size
+            && self.modified == entry.modified.map(seconds)
+            && self.chunk_size == chunk_size
+    }
+
+    /// Location of the record of the upload to `destination`.
+    fn file(destination: &Url) -> Result<PathBuf> {
+        let name = compare::sha1(&mut destination.as_str().as_bytes())?;
+        Ok(state_dir()?.join("uploads").join(name))
+    }
+
+    /// Reads the record at `file`, if any.
+    fn load(file: &PathBuf) -> Option<Progress> {
+        let content = fs::read_to_string(file).ok()?;
+        let mut lines = content.lines();
+        let mut numbers = lines.next()?.split(' ');
+        let size = numbers.next()?.parse().ok()?;
+        let modified = match numbers.next()? {
+            "-" => None,
+            seconds => Some(seconds.parse().ok()?),
+        };
+        let chunk_size = numbers.next()?.parse().ok()?;
+        Some(Progress {
+            size,
+            modified,
+            chunk_size,
+            url: lines.next()?.to_owned(),
+        })
+    }
+
+    fn save(&self, file: &PathBuf) -> Result<()> {
+        if let Some(dir) = file.parent() {
+            fs::create_dir_all(dir).at(dir)?;
+        }
+        let modified = self
+            .modified
+            .map(|m| m.to_string())
+            .unwrap_or_else(|| "-".to_owned());
+        let content = format!(
+            "{} {} {}\n{}\n",
+            self.size, modified, self.chunk_size, self.url
+        );
+        fs::write(file, content).at(file)
+    }
+}
+
+impl WebDavBackend {
+    /// Uploads `entry` to `url` with Nextcloud chunked upload, through a
+    /// collection in the `uploads` collection of the user.
+    pub(super) fn upload_chunks(
+        &self,
+        entry: &Entry,
+        url: &Url,
+        uploads: &Url,
+        mut data: Box<dyn Read + Send>,
+    ) -> Result<()> {
+        let record = Progress::file(url)?;
+        let chunk_size = self.chunk_size(entry.size);
+        let count = entry.size.div_ceil(chunk_size);
+        let chunk_len = |number: u64| chunk_size.min(entry.size - (number - 1) * chunk_size);
+
+        let mut done = 0;
+        let resumed = Progress::load(&record)
+            .filter(|progress| progress.describes(entry, chunk_size))
+            .map(|progress| progress.url);
+        let upload = match resumed.and_then(|upload| Url::parse(&upload).ok()) {
+            Some(upload) => match self.client.chunks(&upload)? {
+                Some(chunks) => {
+                    // Only the chunks before the first missing or partial one count.
+                    for (number, size) in chunks {
+                        if number as u64 != done + 1 || size != chunk_len(number as u64) {
+                            break;
+                        }
+                        done += 1;
+                    }
+                    Some(upload)
+                }
+                None => None,
+            },
+            None => None,
+        };
+        let upload = match upload {
+            Some(upload) => upload,
+            None => {
+                let upload = uploads
+                    .join(&format!("davsync-{}", upload_id(url)?))
+                    .expect("a name is a valid relative URL");
+                self.client.start_chunks(&upload, url)?;
+                Progress::new(entry, chunk_size, &upload).save(&record)?;
+                upload
+            }
+        };
+
+        skip(&mut data, done * chunk_size)?;
+        for number in done + 1..=count {
+            let chunk = read_chunk(&mut data, chunk_len(number))?;
+            self.client
+                .put_chunk(&upload, number as usize, chunk, url, entry.size)?;
+        }
+        let accepted = self.client.finish_chunks(
+            &upload,
+            url,
+            entry.size,
+            entry.checksum.as_deref(),
+            entry.modified,
+        )?;
+        let _ = fs::remove_file(&record);
+        match entry.modified {
+            Some(modified) if !accepted => self.set_modified(url, modified),
+            _ => Ok(()),
+        }
+    }
+
+    /// Uploads `entry` to `url` in ranges written into a hidden temporary
+    /// file, moved into place at the end.
+    pub(super) fn upload_ranges(
+        &self,
+        entry: &Entry,
+        url: &Url,
+        mut data: Box<dyn Read + Send>,
+    ) -> Result<()> {
+        let record = Progress::file(url)?;
+        let chunk_size = self.chunk_size(entry.size);
+        let temporary = self.url(&hidden(&entry.path, "davsync-upload"), false);
+        let progress = Progress::new(entry, chunk_size, &temporary);
+
+        let mut offset = 0;
+        if Progress::load(&record).is_some_and(|recorded| recorded == progress) {
+            if let Some(resource) = self.client.stat(&temporary)? {
+                offset = resource.size.min(entry.size);
+            }
+        } else {
+            progress.save(&record)?;
+        }
+
+        skip(&mut data, offset)?;
+        while offset < entry.size {
+            let chunk = read_chunk(&mut data, chunk_size.min(entry.size - offset))?;
+            let len = chunk.len() as u64;
+            if offset == 0 {
+                self.client.put(&temporary, chunk.into(), None, None)?;
+            } else {
+                self.client
+                    .put_range(&temporary, offset, chunk, entry.size)?;
+            }
+            offset += len;
+        }
+        self.client.mv(&temporary, url)?;
+        let _ = fs::remove_file(&record);
+        match entry.modified {
+            Some(modified) => self.set_modified(url, modified),
+            None => Ok(()),
+        }
+    }
+
+    /// Tells whether the server writes a PUT with `Content-Range` into the
+    /// existing resource, trying once with a small file next to `path`.
+    pub(super) fn takes_partial_put(&self, path: &str) -> Result<bool> {
+        let mut partial_put = self.partial_put.lock().expect("no probe panicked");
+        if let Some(supported) = *partial_put {
+            return Ok(supported);
+        }
+        let probe = self.url(&hidden(path, "davsync-probe"), false);
+        self.client
+            .put(&probe, b"dav".to_vec().into(), None, None)?;
+        let supported = self
+            .client
+            .put_range(&probe, 3, b"sync".to_vec(), 7)
+            .is_ok()
+            && {
+                let mut content = Vec::new();
+                self.client.get(&probe)?.read_to_end(&mut content)?;
+                content == b"davsync"
+            };
+        let _ = self.client.delete(&probe);
+        *partial_put = Some(supported);
+        Ok(supported)
+    }
+
+    /// Tells whether the hidden file at `path` was left by an upload that
+    /// cannot be resumed.
+    pub(super) fn is_stale_upload(&self, path: &str) -> bool {
+        let destination = match visible(path, "davsync-upload") {
+            Some(destination) => destination,
+            None => return false,
+        };
+        let temporary = self.url(path, false);
+        let record = Progress::file(&self.url(&destination, false)).ok();
+        record
+            .and_then(|record| Progress::load(&record))
+            .is_none_or(|progress| progress.url != temporary.as_str())
+    }
+
+    /// Size of the chunks to upload a file of `size` bytes in, so that there
+    /// are not too many of them.
+    fn chunk_size(&self, size: u64) -> u64 {
+        self.settings.chunk_size.max(size.div_ceil(MAX_CHUNKS))
+    }
+}
+
+/// Random-looking name for a new upload collection.
+fn upload_id(url: &Url) -> Result<String> {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    let seed = format!("{}\n{}\n{}", url, now.as_nanos(), std::process::id());
+    Ok(compare::sha1(&mut seed.as_bytes())?[..16].to_owned())
+}
+
+/// Path of a hidden sibling of `path`: `dir/.<name>.<suffix>`.
+pub(super) fn hidden(path: &str, suffix: &str) -> String {
+    match path.rsplit_once('/') {
+        Some((dir, name)) => format!("{}/.{}.{}", dir, name, suffix),
+        None => format!(".{}.{}", path, suffix),
+    }
+}
+
+/// Path of the file the hidden sibling at `path` made by [`hidden`] belongs
+/// to.
+pub(super) fn visible(path: &str, suffix: &str) -> Option<String> {
+    let (dir, name) = match path.rsplit_once('/') {
+        Some((dir, name)) => (Some(dir), name),
+        None => (None, path),
+    };
+    let name = name
+        .strip_prefix('.')?
+        .strip_suffix(suffix)?
+        .strip_suffix('.')
+        .filter(|name| !name.is_empty())?;
+    Some(match dir {
+        Some(dir) => format!("{}/{}", dir, name),
+        None => name.to_owned(),
+    })
+}
+
+/// Reads and drops the first `len` bytes of `data`, already uploaded.
+fn skip(data: &mut dyn Read, len: u64) -> Result<()> {
+    let skipped = io::copy(&mut data.take(len), &mut io::sink())?;
+    if skipped < len {
+        return Err(shrunk());
+    }
+    Ok(())
+}
+
+/// Reads the next `len` bytes of `data`.
+pub(super) fn read_chunk(data: &mut dyn Read, len: u64) -> Result<Vec<u8>> {
+    let mut chunk = Vec::with_capacity(len as usize);
+    data.take(len).read_to_end(&mut chunk)?;
+    if (chunk.len() as u64) < len {
+        return Err(shrunk());
+    }
+    Ok(chunk)
+}
+
+fn shrunk() -> Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "the file got shorter while uploading it",
+    )
+    .into()
+}
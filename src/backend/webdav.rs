@@ -0,0 +1,451 @@
+use std::collections::HashSet;
+use std::io::Read;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use rustydav::prelude::{Body, Url};
+
+use crate::backend::delta::signed_file;
+use crate::backend::upload::{hidden, read_chunk};
+use crate::backend::{is_temporary, join, Backend, Entry, Settings};
+use crate::dav::{self, Client, Credentials, Resource};
+use crate::endpoint::Remote;
+use crate::error::{Error, Result};
+use crate::filter::Filter;
+
+/// A collection or resource on a WebDAV server.
+pub struct WebDavBackend {
+    pub(super) client: Client,
+    root: Url,
+    pub(super) settings: Settings,
+    /// Whether the server refused to set modification times with PROPPATCH.
+    proppatch_refused: AtomicBool,
+    /// Whether the server takes `Content-Range` on PUT, once probed.
+    pub(super) partial_put: Mutex<Option<bool>>,
+    /// Hidden files of interrupted writes and signatures of deleted files
+    /// found while walking.
+    leftovers: Mutex<Vec<String>>,
+}
+
+impl WebDavBackend {
+    pub fn new(remote: &Remote, credentials: Credentials, settings: &Settings) -> Self {
+        // The credentials of the URL are in `credentials`: left in the URL,
+        // they would be sent with Basic authentication from the first request.
+        let mut root = remote.url();
+        let _ = root.set_username("");
+        let _ = root.set_password(None);
+        WebDavBackend {
+            client: Client::new(&settings.http, credentials),
+            root,
+            settings: settings.clone(),
+            proppatch_refused: AtomicBool::new(false),
+            partial_put: Mutex::new(None),
+            leftovers: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Carries a modification time over with PROPPATCH, for servers that
+    /// ignore `X-OC-Mtime`.
+    ///
+    /// Once the server refused every property, it is not asked again.
+    pub(super) fn set_modified(&self, url: &Url, modified: SystemTime) -> Result<()> {
+        if self.proppatch_refused.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        for property in ["getlastmodified", "lastmodified"] {
+            if self.client.set_modified(url, property, modified)? {
+                return Ok(());
+            }
+        }
+        self.proppatch_refused.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Uploads the `size` bytes of `entry` read from `data` to `url`.
+    pub(super) fn upload(
+        &self,
+        entry: &Entry,
+        url: &Url,
+        mut data: Box<dyn Read + Send>,
+    ) -> Result<()> {
+        if entry.size > self.settings.chunk_size {
+            if let Some(uploads) = dav::uploads_url(url) {
+                return self.upload_chunks(entry, url, &uploads, data);
+            }
+            if self.takes_partial_put(&entry.path)? {
+                return self.upload_ranges(entry, url, data);
+            }
+        }
+        // Readers never see a half-written file under its name.
+        let temporary = if self.settings.inplace {
+            None
+        } else {
+            Some(self.url(&hidden(&entry.path, "davsync-upload"), false))
+        };
+        // Held in memory, a body can be sent again if the credentials must be
+        // renewed on the way. Larger ones are streamed.
+        let body = if entry.size <= self.settings.chunk_size {
+            Body::from(read_chunk(&mut data, entry.size)?)
+        } else {
+            Body::sized(data, entry.size)
+        };
+        let accepted = self.client.put(
+            temporary.as_ref().unwrap_or(url),
+            body,
+            entry.checksum.as_deref(),
+            entry.modified,
+        )?;
+        if let Some(temporary) = &temporary {
+            self.client.mv(temporary, url)?;
+        }
+        match entry.modified {
+            Some(modified) if !accepted => self.set_modified(url, modified),
+            _ => Ok(()),
+        }
+    }
+
+    fn leftovers(&self) -> std::sync::MutexGuard<'_, Vec<String>> {
+        self.leftovers.lock().expect("no listing panicked")
+    }
+
+    pub(super) fn url(&self, path: &str, is_collection: bool) -> Url {
+        let mut url = dav::as_collection(&self.root);
+        if path.is_empty() {
+            return if is_collection {
+                url
+            } else {
+                self.root.clone()
+            };
+        }
+        url.path_segments_mut()
+            .expect("WebDAV URLs always have a path")
+            .pop_if_empty()
+            .extend(path.split('/'));
+        if is_collection {
+            url = dav::as_collection(&url);
+        }
+        url
+    }
+}
+
+impl Backend for WebDavBackend {
+    fn location(&self, path: &str) -> String {
+        let mut url = self.url(path, false);
+        let _ = url.set_password(None);
+        url.to_string()
+    }
+
+    fn stat(&self, path: &str) -> Result<Option<Entry>> {
+        Ok(self
+            .client
+            .stat(&self.url(path, false))?
+            .map(|resource| entry(path.to_owned(), resource)))
+    }
+
+    fn list(&self, path: &str) -> Result<Vec<Entry>> {
+        let url = self.url(path, true);
+        let resources = self.client.list(&url)?.ok_or_else(|| not_found(&url))?;
+        Ok(resources
+            .into_iter()
+            .filter(|resource| !is_temporary(name(&resource.path)))
+            .map(|resource| entry(join(path, &resource.path), resource))
+            .collect())
+    }
+
+    fn walk(&self, path: &str, filter: &Filter) -> Result<Vec<Entry>> {
+        let url = self.url(path, true);
+        // Signatures are orphans unless their file is listed as well.
+        let files = Mutex::new(HashSet::new());
+        let signatures = Mutex::new(Vec::new());
+        let keep = |resource: &Resource| {
+            let path = join(path, &resource.path);
+            if is_temporary(name(&path)) {
+                if self.is_stale_upload(&path) {
+                    self.leftovers().push(path);
+                } else if let Some(file) = signed_file(&path) {
+                    signatures
+                        .lock()
+                        .expect("no listing panicked")
+                        .push((path, file));
+                }
+                return false;
+            }
+            if !resource.is_collection {
+                files
+                    .lock()
+                    .expect("no listing panicked")
+                    .insert(path.clone());
+            }
+            !filter.is_excluded(&path, resource.is_collection)
+        };
+        let resources = self
+            .client
+            .scan(&url, &keep, self.settings.jobs)?
+            .ok_or_else(|| not_found(&url))?;
+        let files = files.into_inner().expect("no listing panicked");
+        self.leftovers().extend(
+            signatures
+                .into_inner()
+                .expect("no listing panicked")
+                .into_iter()
+                .filter(|(_, file)| !files.contains(file))
+                .map(|(signature, _)| signature),
+        );
+        Ok(resources
+            .into_iter()
+            .map(|resource| entry(join(path, &resource.path), resource))
+            .collect())
+    }
+
+    fn read(&self, path: &str) -> Result<Box<dyn Read + Send>> {
+        Ok(Box::new(self.client.get(&self.url(path, false))?))
+    }
+
+    fn read_from(
+        &self,
+        entry: &Entry,
+        offset: u64,
+        etag: &str,
+    ) -> Result<(u64, Box<dyn Read + Send>)> {
+        let url = self.url(&entry.path, false);
+        // If-Range needs a strong entity tag.
+        if offset == 0 || etag.starts_with("W/") {
+            return Ok((0, Box::new(self.client.get(&url)?)));
+        }
+        let (offset, response) = self.client.get_from(&url, offset, etag)?;
+        Ok((offset, Box::new(response)))
+    }
+
+    fn checksum(&self, entry: &Entry) -> Result<String> {
+        match &entry.checksum {
+            Some(checksum) => Ok(checksum.clone()),
+            None => self.client.checksum(&self.url(&entry.path, false)),
+        }
+    }
+
+    fn write(&self, entry: &Entry, data: Box<dyn Read + Send>) -> Result<()> {
+        let url = self.url(&entry.path, false);
+        if self.settings.delta {
+            return self.upload_delta(entry, &url, data);
+        }
+        self.upload(entry, &url, data)
+    }
+
+    fn mkdir(&self, path: &str) -> Result<()> {
+        self.client.mkcol(&self.url(path, true))
+    }
+
+    fn delete(&self, path: &str) -> Result<()> {
+        self.client.delete(&self.url(path, false))?;
+        if self.settings.delta {
+            // Directories have no signature, and old files may not have one.
+            let _ = self.client.delete(&self.signature_url(path));
+        }
+        Ok(())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        self.client
+            .mv(&self.url(from, false), &self.url(to, false))?;
+        if self.settings.delta {
+            self.rename_signature(from, to);
+        }
+        Ok(())
+    }
+
+    fn remote_url(&self, path: &str) -> Option<Url> {
+        Some(self.url(path, false))
+    }
+
+    fn credentials(&self) -> Option<&Credentials> {
+        Some(self.client.credentials())
+    }
+
+    fn clean_up(&self) {
+        let mut leftovers = std::mem::take(&mut *self.leftovers());
+        leftovers.sort();
+        leftovers.dedup();
+        for path in leftovers {
+            // Whatever cannot be removed now is found again by the next run.
+            if self.is_stale_upload(&path) || self.is_orphan_signature(&path) {
+                let _ = self.client.delete(&self.url(&path, false));
+            }
+        }
+    }
+
+    fn copy_from(&self, source: &dyn Backend, path: &str) -> Result<bool> {
+        let from = match source.remote_url(path) {
+            Some(from) => from,
+            None => return Ok(false),
+        };
+        let to = self.url(path, false);
+        // The COPY is sent with the credentials of the target, which must
+        // also be the ones allowed to read the source.
+        if !dav::same_server(&from, &to) || source.credentials() != Some(self.client.credentials())
+        {
+            return Ok(false);
+        }
+        match self.client.copy(&from, &to) {
+            Ok(()) => Ok(true),
+            // The server cannot copy there after all, e.g. because of a proxy
+            // or of permissions: the content is streamed instead.
+            Err(Error::Status { status, .. })
+                if (400..500).contains(&status) || status == 501 || status == 502 =>
+            {
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+fn entry(path: String, resource: Resource) -> Entry {
+    Entry {
+        path,
+        is_dir: resource.is_collection,
+        size: resource.size,
+        modified: resource.modified,
+        etag: resource.etag,
+        checksum: resource.checksum,
+    }
+}
+
+/// Last segment of a relative path.
+fn name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+fn not_found(url: &Url) -> Error {
+    dav::status_error("PROPFIND", url, 404)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::DEFAULT_CHUNK_SIZE;
+    use crate::dav::server::{multistatus, Reply, Server};
+    use crate::endpoint::Endpoint;
+
+    fn backend(server: &Server, path: &str, delta: bool) -> WebDavBackend {
+        let remote = match Endpoint::parse(server.url(path).as_str()).unwrap() {
+            Endpoint::Remote(remote) => remote,
+            Endpoint::Local(path) => panic!("{:?} is local", path),
+        };
+        let settings = Settings {
+            http: dav::http_client(&Default::default()).unwrap(),
+            jobs: 1,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            inplace: false,
+            delta,
+        };
+        WebDavBackend::new(&remote, Credentials::Anonymous, &settings)
+    }
+
+    #[test]
+    fn leftovers_of_interrupted_uploads_are_removed_after_a_run() {
+        let server = Server::start(|request| match request.method.as_str() {
+            "PROPFIND" => Reply::new(
+                207,
+                &multistatus(&[
+                    "/dav/",
+                    "/dav/a",
+                    "/dav/.a.davsync-upload",
+                    "/dav/.davsync-upload",
+                    "/dav/sub/",
+                    "/dav/sub/.b.davsync-upload",
+                ]),
+            ),
+            "DELETE" => Reply::new(204, ""),
+            _ => Reply::new(500, ""),
+        });
+        let backend = backend(&server, "dav", false);
+        let entries = backend.walk("", &Filter::default()).unwrap();
+        let paths: Vec<&str> = entries.iter().map(|entry| entry.path.as_str()).collect();
+        assert_eq!(paths, ["a", "sub"]);
+        backend.clean_up();
+        assert_eq!(
+            server.requests(),
+            [
+                "PROPFIND /dav/",
+                "DELETE /dav/.a.davsync-upload",
+                "DELETE /dav/sub/.b.davsync-upload"
+            ]
+        );
+    }
+
+    #[test]
+    fn signatures_of_deleted_files_are_removed_after_a_run() {
+        let server =
+            Server::start(
+                |request| match (request.method.as_str(), request.header("Depth")) {
+                    ("PROPFIND", Some("infinity")) => Reply::new(
+                        207,
+                        &multistatus(&[
+                            "/dav/",
+                            "/dav/a",
+                            "/dav/.a.davsync-sig",
+                            "/dav/.gone.davsync-sig",
+                            "/dav/b.tmp",
+                            "/dav/.b.tmp.davsync-sig",
+                        ]),
+                    ),
+                    ("DELETE", _) => Reply::new(204, ""),
+                    _ => Reply::new(404, ""),
+                },
+            );
+        let backend = backend(&server, "dav", true);
+        let mut filter = Filter::default();
+        filter.add(false, "*.tmp").unwrap();
+        backend.walk("", &filter).unwrap();
+        backend.clean_up();
+        assert_eq!(
+            server.requests(),
+            [
+                "PROPFIND /dav/",
+                "PROPFIND /dav/gone",
+                "DELETE /dav/.gone.davsync-sig"
+            ]
+        );
+    }
+
+    #[test]
+    fn signatures_follow_renamed_files() {
+        let server = Server::start(|request| match request.method.as_str() {
+            "MOVE" if request.path.ends_with("sig") && request.path.contains("unsigned") => {
+                Reply::new(404, "")
+            }
+            "MOVE" => Reply::new(201, ""),
+            _ => Reply::new(204, ""),
+        });
+        let backend = backend(&server, "dav", true);
+        backend.rename("a", "b").unwrap();
+        backend.rename("unsigned", "c").unwrap();
+        let received = server.received();
+        let moves: Vec<String> = received
+            .iter()
+            .map(|request| {
+                format!(
+                    "{} {} {}",
+                    request.method,
+                    request.path,
+                    request.header("Destination").unwrap_or("-")
+                )
+            })
+            .collect();
+        let to = |path: &str| server.url(path).to_string();
+        assert_eq!(
+            moves,
+            [
+                format!("MOVE /dav/a {}", to("dav/b")),
+                format!("MOVE /dav/.a.davsync-sig {}", to("dav/.b.davsync-sig")),
+                format!("MOVE /dav/unsigned {}", to("dav/c")),
+                format!(
+                    "MOVE /dav/.unsigned.davsync-sig {}",
+                    to("dav/.c.davsync-sig")
+                ),
+                "DELETE /dav/.c.davsync-sig -".to_owned(),
+            ]
+        );
+    }
+}
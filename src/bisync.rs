@@ -0,0 +1,978 @@
+//! Two-way synchronization, propagating the changes made on either side since
+//! the previous run.
+//!
+//! What both trees looked like after the previous run is kept in a [`State`].
+//! A path changed on one side only gets the change applied to the other side,
+//! deletions included. A path changed on both sides is a conflict, resolved
+//! according to a [`Policy`], unless both sides made the same change.
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use cli_toolbox::reportln;
+
+use crate::backend::{ancestors, Backend, Entry};
+use crate::compare;
+use crate::error::{Error, Result};
+use crate::ignore::Ignores;
+use crate::pool;
+use crate::state::{Record, State};
+use crate::sync::{self, check_deletions, transfer, transfer_verb, Options, Plan, Stats};
+
+/// One of the two synchronized trees.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Source,
+    Target,
+}
+
+impl Side {
+    fn index(self) -> usize {
+        self as usize
+    }
+
+    fn other(self) -> Side {
+        match self {
+            Side::Source => Side::Target,
+            Side::Target => Side::Source,
+        }
+    }
+}
+
+/// One step of a two-way synchronization plan.
+#[derive(Debug, Clone)]
+pub enum Action {
+    /// Create the directory at this path on a side.
+    Mkdir(Side, String),
+    /// Copy this file to a side from the other one.
+    Transfer(Side, Entry),
+    /// Delete this file or directory, with all its content, from a side.
+    Delete(Side, Entry),
+    /// Rename a file on a side, out of the way of the other side's version.
+    Rename(Side, String, String),
+}
+
+/// What to do with a path changed on both sides, as with `--conflict`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Policy {
+    /// Keep the most recently modified file.
+    Newer,
+    /// Keep the least recently modified file.
+    Older,
+    /// Keep the source version.
+    Source,
+    /// Keep the target version.
+    Target,
+    /// Keep both versions, renaming the older file on both sides.
+    #[default]
+    KeepBoth,
+    /// Ask on the terminal for each conflict.
+    Ask,
+    /// Abort before changing anything.
+    Fail,
+}
+
+impl FromStr for Policy {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<Policy> {
+        Ok(match name {
+            "newer" => Policy::Newer,
+            "older" => Policy::Older,
+            "source" => Policy::Source,
+            "target" => Policy::Target,
+            "keep-both" => Policy::KeepBoth,
+            "ask" => Policy::Ask,
+            "fail" => Policy::Fail,
+            _ => return Err(Error::Usage(format!("invalid conflict policy '{}'", name))),
+        })
+    }
+}
+
+/// A path changed on both sides, and how it was resolved.
+#[derive(Debug, Clone)]
+pub struct Conflict {
+    pub path: String,
+    pub resolution: Resolution,
+}
+
+/// How a conflict is resolved.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Resolution {
+    /// The version of this side replaces the other one.
+    Kept(Side),
+    /// The file of this side is kept on both sides under this new name.
+    Renamed(Side, String),
+    /// Nothing is done, the conflict remains for the next run.
+    Left,
+}
+
+/// Synchronizes the `source` and `target` directories both ways.
+///
+/// In dry-run mode, the plan is reported and nothing is changed, the state
+/// included. Conflicts are listed at the end in any case.
+pub fn run(source: &dyn Backend, target: &dyn Backend, options: &Options) -> Result<Stats> {
+    let mut state = State::load(&source.location(""), &target.location(""))?;
+    Bisync {
+        sides: [source, target],
+        options,
+    }
+    .run(&mut state)
+}
+
+/// Counts a transfer done by a worker and records its result in the state.
+fn record_transfer(
+    result: Result<(Side, Record, Option<Entry>)>,
+    state: &mut State,
+    stats: &mut Stats,
+) -> Result<()> {
+    let (to, record, copy) = result?;
+    stats.transferred += 1;
+    if let Some(copy) = copy {
+        let mut records = [record, Record::new(&copy)];
+        if to == Side::Source {
+            records.swap(0, 1);
+        }
+        state.paths.insert(copy.path, records);
+    }
+    Ok(())
+}
+
+fn count(action: &Action, stats: &mut Stats) {
+    match action {
+        Action::Mkdir(..) => stats.directories += 1,
+        Action::Transfer(..) => stats.transferred += 1,
+        Action::Delete(..) => stats.deleted += 1,
+        Action::Rename(..) => {}
+    }
+}
+
+struct Bisync<'a> {
+    sides: [&'a dyn Backend; 2],
+    options: &'a Options,
+}
+
+/// Everything the planning of a path needs to know.
+struct Trees {
+    entries: [BTreeMap<String, Entry>; 2],
+    /// Paths created or modified on each side since the previous run.
+    modified: [BTreeSet<String>; 2],
+}
+
+impl Trees {
+    fn entry(&self, side: Side, path: &str) -> Option<&Entry> {
+        self.entries[side.index()].get(path)
+    }
+
+    /// Tells whether something below the directory `path` was created or
+    /// modified on `side`.
+    fn has_changes_below(&self, side: Side, path: &str) -> bool {
+        let prefix = format!("{}/", path);
+        self.modified[side.index()]
+            .range(prefix.clone()..)
+            .next()
+            .is_some_and(|changed| changed.starts_with(&prefix))
+    }
+}
+
+impl Bisync<'_> {
+    fn side(&self, side: Side) -> &dyn Backend {
+        self.sides[side.index()]
+    }
+
+    /// Synchronizes both sides from `state`, saved with what was done.
+    fn run(&self, state: &mut State) -> Result<Stats> {
+        let mut stats = Stats::default();
+        let (actions, conflicts) = self.plan(state, &mut stats)?;
+        stats.conflicts = conflicts.len();
+        if self.options.conflict == Policy::Fail && !conflicts.is_empty() {
+            self.summarize(&conflicts);
+            return Err(Error::Aborted(format!(
+                "{} files changed on both sides",
+                conflicts.len()
+            )));
+        }
+
+        let result = if self.options.dry_run {
+            for action in &actions {
+                self.report(action);
+                count(action, &mut stats);
+            }
+            Ok(())
+        } else {
+            // The state is saved even after a failure, to remember what was done.
+            let result = self.execute(&actions, state, &mut stats);
+            state.save()?;
+            if result.is_ok() {
+                for side in self.sides {
+                    side.clean_up();
+                }
+            }
+            result
+        };
+        self.summarize(&conflicts);
+        result.map(|()| stats)
+    }
+
+    /// Compares both trees with the state and returns the actions to perform,
+    /// every directory coming before its content.
+    ///
+    /// The state records of the paths already in sync are updated, and the
+    /// unchanged files counted, on the way. Conflicts are resolved according
+    /// to the policy, the actions taking care of their whole content.
+    fn plan(&self, state: &mut State, stats: &mut Stats) -> Result<(Vec<Action>, Vec<Conflict>)> {
+        let mut actions = Vec::new();
+        let entries = self.walk(&mut actions)?;
+        let modified = [Side::Source, Side::Target].map(|side| {
+            entries[side.index()]
+                .values()
+                .filter(|entry| {
+                    state
+                        .paths
+                        .get(&entry.path)
+                        .is_none_or(|records| records[side.index()].has_changed(entry))
+                })
+                .map(|entry| entry.path.clone())
+                .collect()
+        });
+        let trees = Trees { entries, modified };
+        let mut paths: BTreeSet<String> = state.paths.keys().cloned().collect();
+        for entries in &trees.entries {
+            paths.extend(entries.keys().cloned());
+        }
+
+        // Deleted directories, whose content goes with them.
+        let mut deleted: [HashSet<String>; 2] = Default::default();
+        let mut conflicts = Vec::new();
+        let mut resolved: HashSet<String> = HashSet::new();
+        for path in &paths {
+            if ancestors(path).any(|dir| resolved.contains(dir)) {
+                continue;
+            }
+            let records = state.paths.get(path);
+            let changed = |side: Side| {
+                let entry = trees.entry(side, path);
+                match records.map(|records| &records[side.index()]) {
+                    Some(record) => entry.is_none_or(|entry| record.has_changed(entry)),
+                    None => entry.is_some(),
+                }
+            };
+            let planned = match (changed(Side::Source), changed(Side::Target)) {
+                (false, false) => self.in_sync(path, &trees, state, stats),
+                (true, false) => self.propagate(Side::Source, path, &trees, state, stats),
+                (false, true) => self.propagate(Side::Target, path, &trees, state, stats),
+                (true, true) => self.reconcile(path, &trees, state, stats)?,
+            };
+            let planned = match planned {
+                Some(planned) => planned,
+                None => {
+                    let (resolution, planned) = self.resolve(path, &trees)?;
+                    conflicts.push(Conflict {
+                        path: path.clone(),
+                        resolution,
+                    });
+                    resolved.insert(path.clone());
+                    planned
+                }
+            };
+            for action in planned {
+                if let Action::Delete(side, entry) = &action {
+                    let deleted = &mut deleted[side.index()];
+                    if ancestors(&entry.path).any(|dir| deleted.contains(dir)) {
+                        continue;
+                    }
+                    if entry.is_dir {
+                        deleted.insert(entry.path.clone());
+                    }
+                }
+                actions.push(action);
+            }
+        }
+        self.check_deletions(&actions, &trees)?;
+        Ok((actions, conflicts))
+    }
+
+    /// Aborts when the actions delete more entries of either side than the
+    /// `--max-delete` limits allow, each side judged like the target of a
+    /// one-way synchronization.
+    fn check_deletions(&self, actions: &[Action], trees: &Trees) -> Result<()> {
+        for side in [Side::Source, Side::Target] {
+            let entries = &trees.entries[side.index()];
+            let mut plan = Plan {
+                target_entries: entries.len(),
+                ..Default::default()
+            };
+            for action in actions {
+                let entry = match action {
+                    Action::Delete(deleted, entry) if *deleted == side => entry,
+                    _ => continue,
+                };
+                let prefix = format!("{}/", entry.path);
+                let content = entries
+                    .range(prefix.clone()..)
+                    .take_while(|(path, _)| path.starts_with(&prefix))
+                    .count();
+                plan.removed_entries += 1 + content;
+                plan.actions.push(sync::Action::Delete(entry.clone()));
+            }
+            check_deletions(self.side(side), &plan, self.options)?;
+        }
+        Ok(())
+    }
+
+    /// Lists both trees, applying the filter rules and the ignore files of
+    /// the source to both.
+    fn walk(&self, actions: &mut Vec<Action>) -> Result<[BTreeMap<String, Entry>; 2]> {
+        let (source, target) = (self.side(Side::Source), self.side(Side::Target));
+        let root = source
+            .stat("")?
+            .ok_or_else(|| Error::Usage(format!("'{}' does not exist", source.location(""))))?;
+        let exists = match target.stat("")? {
+            Some(entry) if entry.is_dir && root.is_dir => true,
+            None if root.is_dir => false,
+            _ => {
+                return Err(Error::Usage(format!(
+                    "two-way synchronization needs two directories, '{}' and '{}'",
+                    source.location(""),
+                    target.location("")
+                )))
+            }
+        };
+        if !exists {
+            actions.push(Action::Mkdir(Side::Target, String::new()));
+        }
+
+        let filter = &self.options.filter;
+        let decided = |entry: &Entry| filter.matches(&entry.path, entry.is_dir).is_some();
+        let mut source_entries = source.walk("", filter)?;
+        source_entries.sort_by(|a, b| a.path.cmp(&b.path));
+        let ignores = Ignores::load(
+            source,
+            &self.options.ignore_files,
+            &mut source_entries,
+            &decided,
+        )?;
+        let mut target_entries = if exists {
+            target.walk("", filter)?
+        } else {
+            Vec::new()
+        };
+        target_entries.sort_by(|a, b| a.path.cmp(&b.path));
+        ignores.prune(&mut target_entries, &decided);
+
+        Ok([source_entries, target_entries].map(|entries| {
+            entries
+                .into_iter()
+                .map(|entry| (entry.path.clone(), entry))
+                .collect()
+        }))
+    }
+
+    /// Records a path unchanged on both sides, or changed the same way.
+    fn in_sync(
+        &self,
+        path: &str,
+        trees: &Trees,
+        state: &mut State,
+        stats: &mut Stats,
+    ) -> Option<Vec<Action>> {
+        match (
+            trees.entry(Side::Source, path),
+            trees.entry(Side::Target, path),
+        ) {
+            (Some(source), Some(target)) => {
+                if !source.is_dir {
+                    stats.unchanged += 1;
+                }
+                state
+                    .paths
+                    .insert(path.to_owned(), [Record::new(source), Record::new(target)]);
+            }
+            _ => {
+                state.paths.remove(path);
+            }
+        }
+        Some(Vec::new())
+    }
+
+    /// Applies to the other side the change made on `from`. Returns `None`
+    /// when that would lose changes made on the other side.
+    fn propagate(
+        &self,
+        from: Side,
+        path: &str,
+        trees: &Trees,
+        state: &mut State,
+        stats: &mut Stats,
+    ) -> Option<Vec<Action>> {
+        let to = from.other();
+        let existing = trees.entry(to, path);
+        let entry = match trees.entry(from, path) {
+            Some(entry) => entry,
+            None => {
+                return match existing {
+                    // Deleted on both sides, or gone from `to` since listed.
+                    None => self.in_sync(path, trees, state, stats),
+                    // Something new inside the directory: keep it on both sides.
+                    Some(existing) if existing.is_dir && trees.has_changes_below(to, path) => {
+                        Some(vec![Action::Mkdir(from, path.to_owned())])
+                    }
+                    Some(existing) => Some(vec![Action::Delete(to, existing.clone())]),
+                };
+            }
+        };
+
+        let mut actions = Vec::new();
+        match existing {
+            Some(existing) if existing.is_dir != entry.is_dir => {
+                if existing.is_dir && trees.has_changes_below(to, path) {
+                    return None;
+                }
+                actions.push(Action::Delete(to, existing.clone()));
+            }
+            Some(_) if entry.is_dir => return self.in_sync(path, trees, state, stats),
+            _ => {}
+        }
+        actions.push(if entry.is_dir {
+            Action::Mkdir(to, path.to_owned())
+        } else {
+            Action::Transfer(to, entry.clone())
+        });
+        Some(actions)
+    }
+
+    /// Handles a path changed on both sides, returning `None` unless both
+    /// sides made the same change.
+    fn reconcile(
+        &self,
+        path: &str,
+        trees: &Trees,
+        state: &mut State,
+        stats: &mut Stats,
+    ) -> Result<Option<Vec<Action>>> {
+        let same = match (
+            trees.entry(Side::Source, path),
+            trees.entry(Side::Target, path),
+        ) {
+            (None, None) => true,
+            (Some(source), Some(target)) if source.is_dir && target.is_dir => true,
+            (Some(source), Some(target)) if !source.is_dir && !target.is_dir => {
+                self.identical(source, target)?
+            }
+            _ => false,
+        };
+        if same {
+            Ok(self.in_sync(path, trees, state, stats))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Resolves the conflict at `path` according to the policy.
+    fn resolve(&self, path: &str, trees: &Trees) -> Result<(Resolution, Vec<Action>)> {
+        let entries = [Side::Source, Side::Target].map(|side| trees.entry(side, path));
+        let newer = match entries {
+            [Some(source), Some(target)] if !source.is_dir && !target.is_dir => {
+                match (source.modified, target.modified) {
+                    (Some(source), Some(target)) if source > target => Some(Side::Source),
+                    (Some(source), Some(target)) if source < target => Some(Side::Target),
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+        let winner = match self.options.conflict {
+            Policy::Source => Some(Side::Source),
+            Policy::Target => Some(Side::Target),
+            Policy::Newer => newer,
+            Policy::Older => newer.map(Side::other),
+            Policy::KeepBoth => None,
+            Policy::Ask if self.options.dry_run => return Ok((Resolution::Left, Vec::new())),
+            Policy::Ask => match self.ask(path)? {
+                Some(choice) => choice,
+                None => return Ok((Resolution::Left, Vec::new())),
+            },
+            Policy::Fail => return Ok((Resolution::Left, Vec::new())),
+        };
+        match winner {
+            Some(winner) => Ok((
+                Resolution::Kept(winner),
+                self.overwrite(winner, path, trees),
+            )),
+            None => self.keep_both(path, trees),
+        }
+    }
+
+    /// Asks which side of the conflict at `path` to keep, `None` meaning
+    /// both.
+    fn ask(&self, path: &str) -> Result<Option<Option<Side>>> {
+        if !io::stdin().is_terminal() {
+            return Err(Error::Usage(
+                "--conflict=ask needs a terminal to ask from".to_owned(),
+            ));
+        }
+        loop {
+            eprint!(
+                "conflict: '{}' and '{}' both changed. Keep [s]ource, [t]arget, [b]oth or [l]eave alone? ",
+                self.side(Side::Source).location(path),
+                self.side(Side::Target).location(path)
+            );
+            let mut answer = String::new();
+            if io::stdin().read_line(&mut answer)? == 0 {
+                return Ok(None);
+            }
+            match answer.trim() {
+                "s" | "source" => return Ok(Some(Some(Side::Source))),
+                "t" | "target" => return Ok(Some(Some(Side::Target))),
+                "b" | "both" => return Ok(Some(None)),
+                "l" | "leave" => return Ok(None),
+                _ => {}
+            }
+        }
+    }
+
+    /// Replaces the version of `path` on the other side, with all its
+    /// content, by the one of `winner`.
+    fn overwrite(&self, winner: Side, path: &str, trees: &Trees) -> Vec<Action> {
+        let loser = winner.other();
+        let mut actions = Vec::new();
+        let entry = trees.entry(winner, path);
+        if let Some(existing) = trees.entry(loser, path) {
+            if existing.is_dir || entry.is_none_or(|entry| entry.is_dir) {
+                actions.push(Action::Delete(loser, existing.clone()));
+            }
+        }
+        if let Some(entry) = entry {
+            actions.extend(self.copy_tree(winner, entry, trees));
+        }
+        actions
+    }
+
+    /// Copies `entry` from `from` to the other side, with all its content.
+    fn copy_tree(&self, from: Side, entry: &Entry, trees: &Trees) -> Vec<Action> {
+        let to = from.other();
+        if !entry.is_dir {
+            return vec![Action::Transfer(to, entry.clone())];
+        }
+        let prefix = format!("{}/", entry.path);
+        let content = trees.entries[from.index()]
+            .range(prefix.clone()..)
+            .take_while(|(path, _)| path.starts_with(&prefix))
+            .map(|(_, entry)| entry);
+        std::iter::once(entry)
+            .chain(content)
+            .map(|entry| {
+                if entry.is_dir {
+                    Action::Mkdir(to, entry.path.clone())
+                } else {
+                    Action::Transfer(to, entry.clone())
+                }
+            })
+            .collect()
+    }
+
+    /// Keeps both versions of `path`.
+    ///
+    /// Of two files, the older one, or the target one if they are as old, is
+    /// renamed on its side and copied to the other side, where the newer file
+    /// is copied as well. A file in conflict with a directory is renamed the
+    /// same way. A version in conflict with a deletion is restored.
+    fn keep_both(&self, path: &str, trees: &Trees) -> Result<(Resolution, Vec<Action>)> {
+        let (source, target) = match (
+            trees.entry(Side::Source, path),
+            trees.entry(Side::Target, path),
+        ) {
+            (Some(source), Some(target)) => (source, target),
+            (Some(_), None) => {
+                let actions = self.overwrite(Side::Source, path, trees);
+                return Ok((Resolution::Kept(Side::Source), actions));
+            }
+            _ => {
+                let actions = self.overwrite(Side::Target, path, trees);
+                return Ok((Resolution::Kept(Side::Target), actions));
+            }
+        };
+        let loser = if source.is_dir {
+            Side::Target
+        } else if target.is_dir || source.modified < target.modified {
+            Side::Source
+        } else {
+            Side::Target
+        };
+        let winner = loser.other();
+        let name = conflict_name(path, &self.host(loser), SystemTime::now());
+        let mut renamed = trees
+            .entry(loser, path)
+            .expect("both sides have the path")
+            .clone();
+        renamed.path = name.clone();
+
+        let mut actions = vec![Action::Rename(loser, path.to_owned(), name.clone())];
+        actions.push(Action::Transfer(winner, renamed));
+        let entry = trees.entry(winner, path).expect("both sides have the path");
+        actions.extend(self.copy_tree(winner, entry, trees));
+        Ok((Resolution::Renamed(loser, name), actions))
+    }
+
+    /// Name of the machine holding a side, for conflict file names.
+    fn host(&self, side: Side) -> String {
+        match self.side(side).remote_url("") {
+            Some(url) => url.host_str().unwrap_or("remote").to_owned(),
+            None => hostname(),
+        }
+    }
+
+    /// Tells whether two files changed on both sides ended up the same.
+    ///
+    /// Their checksums are always compared: times are too coarse to tell
+    /// apart two edits made within the same second.
+    fn identical(&self, source: &Entry, target: &Entry) -> Result<bool> {
+        if source.size != target.size {
+            return Ok(false);
+        }
+        let checksum = self.side(Side::Source).checksum(source)?;
+        Ok(checksum == self.side(Side::Target).checksum(target)?)
+    }
+
+    /// Performs the actions, transferring up to `jobs` files at once, and
+    /// records the result in the state.
+    ///
+    /// Other actions are performed in the order of the plan, deletions and
+    /// renames waiting for the transfers planned before them.
+    fn execute(&self, actions: &[Action], state: &mut State, stats: &mut Stats) -> Result<()> {
+        let work = |(to, entry): (Side, &Entry)| -> Result<(Side, Record, Option<Entry>)> {
+            let from = to.other();
+            transfer(self.side(from), self.side(to), entry)?;
+            // The copy gets its own modification time and entity tag.
+            let copy = self.side(to).stat(&entry.path)?;
+            Ok((to, Record::new(entry), copy))
+        };
+        pool::scoped(self.options.jobs, work, |pool| {
+            for action in actions {
+                for result in pool.finished() {
+                    record_transfer(result, state, stats)?;
+                }
+                if let Action::Delete(..) | Action::Rename(..) = action {
+                    while let Some(result) = pool.wait() {
+                        record_transfer(result, state, stats)?;
+                    }
+                }
+                self.report(action);
+                match action {
+                    Action::Mkdir(side, path) => {
+                        self.side(*side).mkdir(path)?;
+                        if !path.is_empty() {
+                            let record = Record::directory();
+                            state.paths.insert(path.clone(), [record.clone(), record]);
+                        }
+                    }
+                    Action::Transfer(to, entry) => {
+                        pool.submit((*to, entry));
+                        continue;
+                    }
+                    Action::Delete(side, entry) => {
+                        self.side(*side).delete(&entry.path)?;
+                        let below = format!("{}/", entry.path);
+                        state
+                            .paths
+                            .retain(|path, _| *path != entry.path && !path.starts_with(&below));
+                    }
+                    Action::Rename(side, from, to) => {
+                        self.side(*side).rename(from, to)?;
+                        state.paths.remove(from);
+                    }
+                }
+                count(action, stats);
+            }
+            while let Some(result) = pool.wait() {
+                record_transfer(result, state, stats)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Reports an action like the one-way synchronization does.
+    fn report(&self, action: &Action) {
+        let (verb, location) = match action {
+            Action::Mkdir(side, path) => ("mkdir", self.side(*side).location(path)),
+            Action::Transfer(to, entry) => {
+                let from = self.side(to.other());
+                (
+                    transfer_verb(from, self.side(*to)),
+                    from.location(&entry.path),
+                )
+            }
+            Action::Delete(side, entry) => ("delete", self.side(*side).location(&entry.path)),
+            Action::Rename(side, path, _) => ("rename", self.side(*side).location(path)),
+        };
+        if self.options.dry_run {
+            reportln!(@terse "{:<8} '{}'", verb, location);
+        } else {
+            reportln!(@verbose "{:<8} '{}'", verb, location);
+        }
+    }
+
+    /// Lists the conflicts and how they were resolved.
+    fn summarize(&self, conflicts: &[Conflict]) {
+        if conflicts.is_empty() {
+            return;
+        }
+        reportln!(@err-terse "{} conflicts:", conflicts.len());
+        for conflict in conflicts {
+            let resolution = match &conflict.resolution {
+                Resolution::Kept(side) => format!("kept the {} version", side),
+                Resolution::Renamed(side, name) => {
+                    format!("kept both, the {} version as '{}'", side, name)
+                }
+                Resolution::Left => "left alone".to_owned(),
+            };
+            reportln!(@err-terse "  '{}': {}", conflict.path, resolution);
+        }
+    }
+}
+
+impl fmt::Display for Side {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Side::Source => write!(f, "source"),
+            Side::Target => write!(f, "target"),
+        }
+    }
+}
+
+/// Name a conflicting file at `path` is renamed to:
+/// `name.conflict-<host>-<UTC time>.ext`.
+fn conflict_name(path: &str, host: &str, time: SystemTime) -> String {
+    let (dir, name) = match path.rfind('/') {
+        Some(i) => (&path[..=i], &path[i + 1..]),
+        None => ("", path),
+    };
+    // A leading dot starts a hidden name, not an extension.
+    let (stem, extension) = match name.rfind('.').filter(|&i| i > 0) {
+        Some(i) => name.split_at(i),
+        None => (name, ""),
+    };
+    format!(
+        "{}{}.conflict-{}-{}{}",
+        dir,
+        stem,
+        host,
+        timestamp(time),
+        extension
+    )
+}
+
+/// Formats `time` as `YYYYMMDD-HHMMSS` in UTC.
+fn timestamp(time: SystemTime) -> String {
+    let seconds = compare::seconds(time);
+    let (days, seconds) = (seconds / 86400, seconds % 86400);
+    // Civil date from days since 1970-01-01, after Howard Hinnant's
+    // `civil_from_days`.
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as u64;
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/// Name of the local machine.
+fn hostname() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .or_else(|_| fs::read_to_string("/etc/hostname"))
+        .ok()
+        .or_else(|| env::var("HOSTNAME").ok())
+        .map(|name| name.trim().to_owned())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "localhost".to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use std::process;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::*;
+    use crate::backend::MemoryBackend;
+
+    fn at(seconds: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    /// A state file of the test `name`, removed if left by an earlier run.
+    fn state_file(name: &str) -> PathBuf {
+        let file = env::temp_dir().join(format!("davsync-{}-{}.state", process::id(), name));
+        let _ = fs::remove_file(&file);
+        file
+    }
+
+    /// Synchronizes `source` and `target` from the state saved in `file`.
+    fn bisync(
+        source: &MemoryBackend,
+        target: &MemoryBackend,
+        file: &Path,
+        conflict: Policy,
+    ) -> Result<Stats> {
+        let options = Options {
+            conflict,
+            jobs: 1,
+            ..Default::default()
+        };
+        let bisync = Bisync {
+            sides: [source, target],
+            options: &options,
+        };
+        bisync.run(&mut State::read(file.to_owned())?)
+    }
+
+    /// Both sides after a conflict on `file.txt` under `policy`: an older edit
+    /// on the target, a newer one on the source.
+    fn conflict(policy: Policy) -> (MemoryBackend, MemoryBackend, Result<Stats>) {
+        let file = state_file(&format!("conflict-{:?}", policy));
+        let (source, target) = (MemoryBackend::new(), MemoryBackend::new());
+        let source = source.file("file.txt", "base", 10);
+        bisync(&source, &target, &file, policy).unwrap();
+        let source = source.file("file.txt", "source", 30);
+        let target = target.file("file.txt", "target!", 20);
+        let result = bisync(&source, &target, &file, policy);
+        fs::remove_file(file).unwrap();
+        (source, target, result)
+    }
+
+    #[test]
+    fn timestamps_are_utc_dates() {
+        assert_eq!(timestamp(UNIX_EPOCH), "19700101-000000");
+        assert_eq!(timestamp(at(951_782_400)), "20000229-000000");
+        assert_eq!(timestamp(at(1_709_251_199)), "20240229-235959");
+        assert_eq!(timestamp(at(4_102_444_800)), "21000101-000000");
+    }
+
+    #[test]
+    fn conflict_names_keep_the_extension() {
+        let time = at(1_700_000_000);
+        assert_eq!(
+            conflict_name("dir/report.pdf", "laptop", time),
+            "dir/report.conflict-laptop-20231114-221320.pdf"
+        );
+        assert_eq!(
+            conflict_name("archive.tar.gz", "host", time),
+            "archive.tar.conflict-host-20231114-221320.gz"
+        );
+        assert_eq!(
+            conflict_name("a.b/Makefile", "host", time),
+            "a.b/Makefile.conflict-host-20231114-221320"
+        );
+        assert_eq!(
+            conflict_name(".bashrc", "host", time),
+            ".bashrc.conflict-host-20231114-221320"
+        );
+    }
+
+    #[test]
+    fn changes_are_propagated_both_ways() {
+        let file = state_file("propagation");
+        let source = MemoryBackend::new()
+            .file("a", "1", 10)
+            .file("dir/b", "b", 10);
+        let target = MemoryBackend::new();
+        bisync(&source, &target, &file, Policy::Fail).unwrap();
+        assert_eq!(target.paths(), ["a", "dir", "dir/b"]);
+
+        let source = source.file("a", "22", 20);
+        let target = target.file("c", "c", 10);
+        target.delete("dir/b").unwrap();
+        let stats = bisync(&source, &target, &file, Policy::Fail).unwrap();
+        assert_eq!((stats.transferred, stats.deleted), (2, 1));
+        assert_eq!(source.paths(), ["a", "c", "dir"]);
+        assert_eq!(target.paths(), ["a", "c", "dir"]);
+        assert_eq!(target.content("a").as_deref(), Some("22"));
+        assert_eq!(source.content("c").as_deref(), Some("c"));
+
+        source.delete("a").unwrap();
+        target.delete("dir").unwrap();
+        bisync(&source, &target, &file, Policy::Fail).unwrap();
+        assert_eq!(source.paths(), ["c"]);
+        assert_eq!(target.paths(), ["c"]);
+
+        let stats = bisync(&source, &target, &file, Policy::Fail).unwrap();
+        assert_eq!((stats.transferred, stats.unchanged), (0, 1));
+        fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn conflicts_are_resolved_by_the_policy() {
+        for (policy, kept) in [
+            (Policy::Newer, "source"),
+            (Policy::Older, "target!"),
+            (Policy::Source, "source"),
+            (Policy::Target, "target!"),
+        ] {
+            let (source, target, result) = conflict(policy);
+            assert_eq!(result.unwrap().conflicts, 1);
+            for side in [&source, &target] {
+                assert_eq!(side.paths(), ["file.txt"]);
+                assert_eq!(
+                    side.content("file.txt").as_deref(),
+                    Some(kept),
+                    "{:?}",
+                    policy
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn keep_both_renames_the_older_file() {
+        let (source, target, result) = conflict(Policy::KeepBoth);
+        assert_eq!(result.unwrap().conflicts, 1);
+        for side in [&source, &target] {
+            let paths = side.paths();
+            assert_eq!(paths.len(), 2);
+            assert!(paths[0].starts_with("file.conflict-") && paths[0].ends_with(".txt"));
+            assert_eq!(side.content(&paths[0]).as_deref(), Some("target!"));
+            assert_eq!(side.content("file.txt").as_deref(), Some("source"));
+        }
+    }
+
+    #[test]
+    fn fail_changes_nothing() {
+        let (source, target, result) = conflict(Policy::Fail);
+        assert!(matches!(result, Err(Error::Aborted(_))));
+        assert_eq!(source.content("file.txt").as_deref(), Some("source"));
+        assert_eq!(target.content("file.txt").as_deref(), Some("target!"));
+    }
+
+    #[test]
+    fn new_content_keeps_a_deleted_directory() {
+        let file = state_file("deleted-directory");
+        let source = MemoryBackend::new().file("dir/old", "a", 10);
+        let target = MemoryBackend::new();
+        bisync(&source, &target, &file, Policy::Fail).unwrap();
+
+        source.delete("dir").unwrap();
+        let target = target.file("dir/new", "n", 20);
+        bisync(&source, &target, &file, Policy::Fail).unwrap();
+        assert_eq!(source.paths(), ["dir", "dir/new"]);
+        assert_eq!(target.paths(), ["dir", "dir/new"]);
+        fs::remove_file(file).unwrap();
+    }
+}
@@ -0,0 +1,87 @@
+//! Detection of files that differ between the source and the target.
+
+use std::io::{self, Read};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use sha1::{Digest, Sha1};
+
+use crate::backend::Entry;
+
+/// rsync-like "quick check": tells whether the target file is up to date.
+///
+/// The sizes must be equal and the modification times must not differ by
+/// more than `window`, either way. Times are compared in whole seconds, the
+/// precision of the HTTP dates WebDAV servers use for `getlastmodified`.
+pub fn quick_check(source: &Entry, target: &Entry, window: Duration) -> bool {
+    if source.size != target.size {
+        return false;
+    }
+    match (source.modified, target.modified) {
+        (Some(source), Some(target)) => {
+            seconds(source).abs_diff(seconds(target)) <= window.as_secs()
+        }
+        _ => false,
+    }
+}
+
+/// Computes the SHA-1 checksum of `data` as lowercase hexadecimal, reading it
+/// in chunks so that large files are never loaded in memory.
+pub fn sha1<R: Read + ?Sized>(data: &mut R) -> io::Result<String> {
+    let mut hasher = Sha1::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        match data.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => hasher.update(&buffer[..n]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+/// Whole seconds since the Unix epoch, 0 for earlier times.
+pub(crate) fn seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(size: u64, seconds: u64) -> Entry {
+        Entry {
+            path: "file".to_owned(),
+            is_dir: false,
+            size,
+            modified: Some(UNIX_EPOCH + Duration::from_secs(seconds)),
+            etag: None,
+            checksum: None,
+        }
+    }
+
+    #[test]
+    fn quick_check_compares_times_both_ways() {
+        let window = Duration::from_secs(2);
+        assert!(quick_check(&file(3, 100), &file(3, 100), Duration::ZERO));
+        assert!(quick_check(&file(3, 100), &file(3, 102), window));
+        assert!(quick_check(&file(3, 102), &file(3, 100), window));
+        assert!(!quick_check(&file(3, 100), &file(3, 103), window));
+        assert!(!quick_check(&file(3, 103), &file(3, 100), window));
+        assert!(!quick_check(&file(3, 100), &file(4, 100), window));
+    }
+
+    #[test]
+    fn sha1_is_lowercase_hexadecimal() {
+        assert_eq!(
+            sha1(&mut &b"abc"[..]).unwrap(),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+    }
+}
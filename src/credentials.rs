@@ -0,0 +1,154 @@
+//! Where the credentials of remote endpoints come from.
+//!
+//! The user name is the one in the URL, or `--user`, or the `DAVSYNC_USER`
+//! environment variable. The password is the one in the URL, or the first
+//! line of `--password-file`, or `DAVSYNC_PASSWORD`. Failing these, the
+//! login and password of the host are looked up in `~/.netrc` or the file
+//! given with `--netrc-file`, and the password is asked for on the terminal
+//! as a last resort.
+//!
+//! With a bearer token, no user or password is looked for.
+
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::PathBuf;
+
+use crate::dav::{Credentials, TokenSource};
+use crate::endpoint::Remote;
+use crate::error::{Error, Result};
+use crate::netrc::Netrc;
+
+/// The credentials given on the command line, for every remote endpoint.
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    pub user: Option<String>,
+    pub password_file: Option<PathBuf>,
+    pub bearer: Option<TokenSource>,
+    /// Netrc file to use instead of `~/.netrc`.
+    pub netrc_file: Option<PathBuf>,
+}
+
+/// Finds the credentials to log in to `remote` with.
+pub fn resolve(remote: &Remote, options: &Options) -> Result<Credentials> {
+    if let Some(source) = &options.bearer {
+        return Ok(Credentials::Bearer(source.clone()));
+    }
+    let user = Some(remote.username())
+        .filter(|user| !user.is_empty())
+        .or_else(|| options.user.clone())
+        .or_else(|| env::var("DAVSYNC_USER").ok())
+        .filter(|user| !user.is_empty());
+    let password = match remote.base.password() {
+        Some(_) => Some(remote.password()),
+        None => match &options.password_file {
+            Some(file) => Some(read_password_file(file)?),
+            None => env::var("DAVSYNC_PASSWORD").ok(),
+        },
+    };
+    if let (Some(user), Some(password)) = (&user, &password) {
+        return Ok(Credentials::Password {
+            user: user.clone(),
+            password: password.clone(),
+        });
+    }
+
+    let netrc = load_netrc(options)?;
+    let host = remote.base.host_str().unwrap_or_default();
+    let machine = netrc.find(host, user.as_deref());
+    let user = match user.or_else(|| machine.and_then(|machine| machine.login.clone())) {
+        Some(user) => user,
+        None => return Ok(Credentials::Anonymous),
+    };
+    let password = match password.or_else(|| machine.and_then(|machine| machine.password.clone())) {
+        Some(password) => password,
+        None => prompt(&user, remote)?,
+    };
+    Ok(Credentials::Password { user, password })
+}
+
+/// Reads the netrc file given on the command line, or `~/.netrc` if any.
+fn load_netrc(options: &Options) -> Result<Netrc> {
+    match &options.netrc_file {
+        Some(file) => Netrc::load(file, true),
+        None => match env::var_os("HOME") {
+            Some(home) => Netrc::load(&PathBuf::from(home).join(".netrc"), false),
+            None => Ok(Netrc::default()),
+        },
+    }
+}
+
+/// Reads the first line of a password file.
+fn read_password_file(file: &PathBuf) -> Result<String> {
+    let content = fs::read_to_string(file).map_err(|e| {
+        Error::Usage(format!(
+            "cannot read password file '{}': {}",
+            file.display(),
+            e
+        ))
+    })?;
+    Ok(content.lines().next().unwrap_or_default().to_owned())
+}
+
+/// Asks for the password of `user` on the terminal, without echoing it.
+fn prompt(user: &str, remote: &Remote) -> Result<String> {
+    let host = remote.base.host_str().unwrap_or_default();
+    let stdin = io::stdin();
+    if !stdin.is_terminal() {
+        return Err(Error::Usage(format!(
+            "no password for {}@{}: give it with --password-file, DAVSYNC_PASSWORD or a netrc file",
+            user, host
+        )));
+    }
+    eprint!("Password for {}@{}: ", user, host);
+    io::stderr().flush()?;
+    let mut password = String::new();
+    let read = {
+        let _echo = Echo::off();
+        stdin.lock().read_line(&mut password)
+    };
+    eprintln!();
+    read?;
+    Ok(password.trim_end_matches(['\n', '\r']).to_owned())
+}
+
+/// Turns terminal echo off until dropped.
+struct Echo {
+    #[cfg(unix)]
+    saved: Option<libc::termios>,
+}
+
+impl Echo {
+    #[cfg(unix)]
+    fn off() -> Echo {
+        // SAFETY: termios is plain data, filled by tcgetattr before use.
+        unsafe {
+            let mut termios: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut termios) != 0 {
+                return Echo { saved: None };
+            }
+            let saved = termios;
+            termios.c_lflag &= !libc::ECHO;
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios);
+            Echo { saved: Some(saved) }
+        }
+    }
+
+    /// Elsewhere, the password shows as it is typed.
+    #[cfg(not(unix))]
+    fn off() -> Echo {
+        Echo {}
+    }
+}
+
+impl Drop for Echo {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Some(saved) = &self.saved {
+            // SAFETY: restores the settings tcgetattr returned.
+            unsafe {
+                libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, saved);
+            }
+        }
+    }
+}
@@ -0,0 +1,464 @@
+//! HTTP authentication: Basic, Digest when the server asks for it, and
+//! bearer tokens.
+//!
+//! Passwords are only sent once the server asked for them: the first request
+//! goes without credentials, and the scheme of the 401 challenge in
+//! `WWW-Authenticate` is kept for the following ones. Digest (RFC 7616, MD5
+//! and MD5-sess algorithms, with or without `qop=auth`) is preferred when
+//! offered, so that the password never travels in clear to a server that
+//! does not ask for it.
+//!
+//! Bearer tokens read from a file or printed by a command are read again
+//! when the server answers 401, as they expire.
+
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use md5::{Digest as _, Md5};
+use reqwest::blocking::Request;
+use reqwest::header::{HeaderValue, AUTHORIZATION, WWW_AUTHENTICATE};
+use rustydav::prelude::Response;
+
+use crate::compare;
+use crate::error::{Error, Result};
+
+/// How a client proves who it is.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Credentials {
+    #[default]
+    Anonymous,
+    Password {
+        user: String,
+        password: String,
+    },
+    Bearer(TokenSource),
+}
+
+/// Where a bearer token comes from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TokenSource {
+    Fixed(String),
+    /// The first line of a file.
+    File(PathBuf),
+    /// What a shell command prints.
+    Command(String),
+}
+
+impl TokenSource {
+    /// Reads the current token.
+    pub fn fetch(&self) -> Result<String> {
+        let token = match self {
+            TokenSource::Fixed(token) => token.clone(),
+            TokenSource::File(file) => fs::read_to_string(file).map_err(|e| {
+                Error::Usage(format!(
+                    "cannot read token file '{}': {}",
+                    file.display(),
+                    e
+                ))
+            })?,
+            TokenSource::Command(command) => {
+                let output = shell(command).output().map_err(|e| {
+                    Error::Usage(format!("cannot run token command '{}': {}", command, e))
+                })?;
+                if !output.status.success() {
+                    return Err(Error::Usage(format!(
+                        "token command '{}' failed with {}",
+                        command, output.status
+                    )));
+                }
+                String::from_utf8_lossy(&output.stdout).into_owned()
+            }
+        };
+        Ok(token.lines().next().unwrap_or_default().trim().to_owned())
+    }
+}
+
+/// The credentials of a client and the scheme the server wants them in.
+#[derive(Debug)]
+pub(super) struct Auth {
+    credentials: Credentials,
+    /// How the server asked for the password, once it did.
+    scheme: Mutex<Option<Scheme>>,
+    /// The bearer token last read.
+    token: Mutex<Option<String>>,
+}
+
+/// An authentication scheme a server asked for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Scheme {
+    Basic,
+    Digest(Digest),
+}
+
+/// A Digest challenge and the number of requests answering it so far.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Digest {
+    realm: String,
+    nonce: String,
+    opaque: Option<String>,
+    /// Whether the server offered the `auth` quality of protection.
+    qop: bool,
+    /// Whether the algorithm is MD5-sess rather than MD5.
+    session: bool,
+    count: u32,
+}
+
+impl Auth {
+    pub(super) fn new(credentials: Credentials) -> Self {
+        Auth {
+            credentials,
+            scheme: Mutex::new(None),
+            token: Mutex::new(None),
+        }
+    }
+
+    pub(super) fn credentials(&self) -> &Credentials {
+        &self.credentials
+    }
+
+    /// Adds the `Authorization` header to `request`, if there are credentials
+    /// and, for a password, once the server told how it wants it.
+    pub(super) fn authorize(&self, request: &mut Request) -> Result<()> {
+        let value = match &self.credentials {
+            Credentials::Anonymous => return Ok(()),
+            Credentials::Password { user, password } => {
+                match &mut *self.scheme.lock().expect("no request panicked") {
+                    None => return Ok(()),
+                    Some(Scheme::Basic) => format!(
+                        "Basic {}",
+                        STANDARD.encode(format!("{}:{}", user, password))
+                    ),
+                    Some(Scheme::Digest(digest)) => digest.authorization(user, password, request),
+                }
+            }
+            Credentials::Bearer(source) => {
+                let mut token = self.token.lock().expect("no request panicked");
+                if token.is_none() {
+                    *token = Some(source.fetch()?);
+                }
+                format!("Bearer {}", token.as_deref().unwrap_or_default())
+            }
+        };
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            request.headers_mut().insert(AUTHORIZATION, value);
+        }
+        Ok(())
+    }
+
+    /// Reads the bearer token again, if it comes from a file or a command.
+    pub(super) fn refresh(&self) -> Result<()> {
+        if let Credentials::Bearer(source @ (TokenSource::File(_) | TokenSource::Command(_))) =
+            &self.credentials
+        {
+            let fresh = source.fetch()?;
+            *self.token.lock().expect("no request panicked") = Some(fresh);
+        }
+        Ok(())
+    }
+
+    /// Reads a 401 `response` to a request sent with the `Authorization`
+    /// header `sent`, and tells whether the request is worth sending again:
+    /// the server asks for a password that was not sent yet, or for Digest
+    /// authentication with a nonce that was not tried yet, or the bearer
+    /// token changed.
+    pub(super) fn challenged(
+        &self,
+        response: &Response,
+        sent: Option<&HeaderValue>,
+    ) -> Result<bool> {
+        match &self.credentials {
+            Credentials::Anonymous => Ok(false),
+            Credentials::Password { .. } => Ok(self.password_challenged(response, sent)),
+            Credentials::Bearer(TokenSource::Fixed(_)) => Ok(false),
+            Credentials::Bearer(source) => {
+                let mut token = self.token.lock().expect("no request panicked");
+                let current = token.as_ref().map(|token| format!("Bearer {}", token));
+                // Another request may have refreshed it in the meantime.
+                if current.as_deref().map(str::as_bytes) != sent.map(HeaderValue::as_bytes) {
+                    return Ok(true);
+                }
+                let fresh = source.fetch()?;
+                let changed = token.as_ref() != Some(&fresh);
+                *token = Some(fresh);
+                Ok(changed)
+            }
+        }
+    }
+
+    fn password_challenged(&self, response: &Response, sent: Option<&HeaderValue>) -> bool {
+        let challenges: Vec<&str> = response
+            .headers()
+            .get_all(WWW_AUTHENTICATE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .collect();
+        let offered = match challenges.iter().find_map(|header| Digest::parse(header)) {
+            Some(digest) => Scheme::Digest(digest),
+            None if challenges.iter().any(|header| offers_basic(header)) => Scheme::Basic,
+            None => return false,
+        };
+        let mut scheme = self.scheme.lock().expect("no request panicked");
+        let answered = match (&*scheme, &offered) {
+            (Some(Scheme::Digest(current)), Scheme::Digest(digest)) => {
+                current.nonce == digest.nonce
+            }
+            (Some(Scheme::Basic), Scheme::Basic) => true,
+            _ => false,
+        };
+        if answered {
+            // Sent without credentials, the request went before another one
+            // learnt the scheme. Otherwise the credentials are wrong.
+            return sent.is_none();
+        }
+        *scheme = Some(offered);
+        true
+    }
+}
+
+impl Digest {
+    /// Finds a Digest challenge using a supported algorithm in a
+    /// `WWW-Authenticate` header value, which may hold several challenges.
+    fn parse(header: &str) -> Option<Digest> {
+        let mut rest = header;
+        loop {
+            let (scheme, params, next) = challenge(rest)?;
+            rest = next;
+            if !scheme.eq_ignore_ascii_case("Digest") {
+                continue;
+            }
+            let param = |name: &str| {
+                params
+                    .iter()
+                    .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                    .map(|(_, value)| value.clone())
+            };
+            let session = match param("algorithm").as_deref().map(str::to_ascii_uppercase) {
+                None => false,
+                Some(algorithm) if algorithm == "MD5" => false,
+                Some(algorithm) if algorithm == "MD5-SESS" => true,
+                Some(_) => continue,
+            };
+            let qop = match param("qop") {
+                None => false,
+                Some(qop) if qop.split(',').any(|qop| qop.trim() == "auth") => true,
+                // Only auth-int, which would need the hash of every body.
+                Some(_) => continue,
+            };
+            return Some(Digest {
+                realm: param("realm").unwrap_or_default(),
+                nonce: param("nonce")?,
+                opaque: param("opaque"),
+                qop,
+                session,
+                count: 0,
+            });
+        }
+    }
+
+    /// Builds the `Authorization` value of the next request.
+    fn authorization(&mut self, user: &str, password: &str, request: &Request) -> String {
+        let url = request.url();
+        let uri = match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_owned(),
+        };
+        let cnonce = cnonce(self.count + 1);
+        self.answer(user, password, request.method().as_str(), &uri, &cnonce)
+    }
+
+    /// Builds the `Authorization` value of the next request, a `method` on
+    /// `uri`, with the client nonce `cnonce`.
+    fn answer(
+        &mut self,
+        user: &str,
+        password: &str,
+        method: &str,
+        uri: &str,
+        cnonce: &str,
+    ) -> String {
+        self.count += 1;
+        let count = format!("{:08x}", self.count);
+
+        let mut ha1 = md5_hex(&format!("{}:{}:{}", user, self.realm, password));
+        if self.session {
+            ha1 = md5_hex(&format!("{}:{}:{}", ha1, self.nonce, cnonce));
+        }
+        let ha2 = md5_hex(&format!("{}:{}", method, uri));
+        let response = if self.qop {
+            md5_hex(&format!(
+                "{}:{}:{}:{}:auth:{}",
+                ha1, self.nonce, count, cnonce, ha2
+            ))
+        } else {
+            md5_hex(&format!("{}:{}:{}", ha1, self.nonce, ha2))
+        };
+
+        let mut value = format!(
+            r#"Digest username="{}", realm="{}", nonce="{}", uri="{}", algorithm={}, response="{}""#,
+            quote(user),
+            quote(&self.realm),
+            quote(&self.nonce),
+            quote(uri),
+            if self.session { "MD5-sess" } else { "MD5" },
+            response
+        );
+        if self.qop {
+            value.push_str(&format!(r#", qop=auth, nc={}, cnonce="{}""#, count, cnonce));
+        }
+        if let Some(opaque) = &self.opaque {
+            value.push_str(&format!(r#", opaque="{}""#, quote(opaque)));
+        }
+        value
+    }
+}
+
+/// Tells whether a `WWW-Authenticate` header value offers Basic
+/// authentication among its challenges.
+fn offers_basic(header: &str) -> bool {
+    let mut rest = header;
+    while let Some((scheme, _, next)) = challenge(rest) {
+        if scheme.eq_ignore_ascii_case("Basic") {
+            return true;
+        }
+        rest = next;
+    }
+    false
+}
+
+/// Names and values of the parameters of a challenge.
+type Params = Vec<(String, String)>;
+
+/// Splits the first challenge off a `WWW-Authenticate` value: its scheme, its
+/// parameters and what follows.
+fn challenge(header: &str) -> Option<(&str, Params, &str)> {
+    let header = header.trim_start_matches([' ', ',']);
+    let end = header.find(' ').unwrap_or(header.len());
+    let scheme = &header[..end];
+    if scheme.is_empty() {
+        return None;
+    }
+    let mut rest = &header[end..];
+    let mut params = Vec::new();
+    loop {
+        let trimmed = rest.trim_start_matches([' ', ',']);
+        let name_end = trimmed.find(['=', ' ', ',']).unwrap_or(trimmed.len());
+        let after = trimmed[name_end..].trim_start_matches(' ');
+        // A name without a value starts the next challenge.
+        if name_end == 0 || !after.starts_with('=') {
+            return Some((scheme, params, trimmed));
+        }
+        let name = trimmed[..name_end].to_owned();
+        let after = after[1..].trim_start_matches(' ');
+        let (value, next) = match after.strip_prefix('"') {
+            Some(quoted) => {
+                let mut value = String::new();
+                let mut chars = quoted.char_indices();
+                let mut end = quoted.len();
+                while let Some((i, c)) = chars.next() {
+                    match c {
+                        '\\' => value.extend(chars.next().map(|(_, c)| c)),
+                        '"' => {
+                            end = i + 1;
+                            break;
+                        }
+                        c => value.push(c),
+                    }
+                }
+                (value, &quoted[end..])
+            }
+            None => {
+                let end = after.find(',').unwrap_or(after.len());
+                (after[..end].trim().to_owned(), &after[end..])
+            }
+        };
+        params.push((name, value));
+        rest = next;
+    }
+}
+
+/// Runs `command` with the shell.
+fn shell(command: &str) -> Command {
+    if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.args(["/C", command]);
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.args(["-c", command]);
+        shell
+    }
+}
+
+/// Escapes a value for a quoted string.
+fn quote(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// A client nonce that differs for every request.
+fn cnonce(count: u32) -> String {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    let seed = format!("{}:{}:{}", now.as_nanos(), std::process::id(), count);
+    let hash = compare::sha1(&mut seed.as_bytes()).expect("reading memory never fails");
+    hash[..16].to_owned()
+}
+
+fn md5_hex(data: &str) -> String {
+    Md5::digest(data.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn digest_answers_the_rfc_2617_example() {
+        let mut digest = Digest::parse(
+            r#"Digest realm="testrealm@host.com", qop="auth,auth-int", nonce="dcd98b7102dd2f0e8b11d0f600bfb0c093", opaque="5ccc069c403ebaf9f0171e9517f40e41""#,
+        )
+        .unwrap();
+        assert!(digest.qop && !digest.session);
+        let value = digest.answer(
+            "Mufasa",
+            "Circle Of Life",
+            "GET",
+            "/dir/index.html",
+            "0a4f113b",
+        );
+        assert!(value.contains(r#"response="6629fae49393a05397450978507c4ef1""#));
+        assert!(value.contains(r#"qop=auth, nc=00000001, cnonce="0a4f113b""#));
+        assert!(value.contains(r#"opaque="5ccc069c403ebaf9f0171e9517f40e41""#));
+    }
+
+    #[test]
+    fn digest_skips_unsupported_challenges() {
+        assert_eq!(Digest::parse(r#"Basic realm="dav""#), None);
+        assert_eq!(
+            Digest::parse(r#"Digest nonce="n", algorithm=SHA-256"#),
+            None
+        );
+        assert_eq!(Digest::parse(r#"Digest nonce="n", qop="auth-int""#), None);
+        let digest = Digest::parse(
+            r#"Basic realm="dav", Digest realm="dav", nonce="n", algorithm=MD5-sess"#,
+        )
+        .unwrap();
+        assert_eq!((digest.nonce.as_str(), digest.session), ("n", true));
+    }
+
+    #[test]
+    fn basic_is_found_among_challenges() {
+        assert!(offers_basic(
+            r#"Digest realm="dav", nonce="n", Basic realm="dav""#
+        ));
+        assert!(!offers_basic(r#"Bearer realm="dav""#));
+    }
+}
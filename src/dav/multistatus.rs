@@ -0,0 +1,286 @@
+//! Parsing of `207 Multi-Status` PROPFIND responses.
+
+use std::io::Read;
+use std::time::SystemTime;
+
+use percent_encoding::percent_decode_str;
+use rustydav::prelude::Url;
+use xmltree::Element;
+
+use crate::dav::sha1_checksum;
+use crate::error::Result;
+
+const DAV_NS: &str = "DAV:";
+const OC_NS: &str = "http://owncloud.org/ns";
+
+/// Body of the PROPFIND requests, asking only for the properties davsync uses.
+pub const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8" ?>
+<D:propfind xmlns:D="DAV:" xmlns:oc="http://owncloud.org/ns">
+  <D:prop>
+    <D:resourcetype/>
+    <D:getcontentlength/>
+    <D:getlastmodified/>
+    <D:getetag/>
+    <oc:checksums/>
+  </D:prop>
+</D:propfind>
+"#;
+
+/// A resource described in a multistatus response.
+#[derive(Debug, Clone)]
+pub struct Resource {
+    /// Decoded path of the resource relative to the requested URL, empty for
+    /// the requested resource itself.
+    pub path: String,
+    pub is_collection: bool,
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+    pub etag: Option<String>,
+    /// SHA-1 checksum stored by ownCloud and Nextcloud servers.
+    pub checksum: Option<String>,
+}
+
+/// Parses a multistatus body answering a PROPFIND on `url`.
+///
+/// Resources whose href is not below `url` are ignored.
+pub fn parse<R: Read>(body: R, url: &Url) -> Result<Vec<Resource>> {
+    let multistatus = Element::parse(body)?;
+    let root = decode(url.path());
+    let root = root.trim_end_matches('/');
+
+    let mut resources = Vec::new();
+    for response in children(&multistatus, "response") {
+        let href = match response
+            .get_child(("href", DAV_NS))
+            .and_then(|e| e.get_text())
+        {
+            Some(href) => href.trim().to_owned(),
+            None => continue,
+        };
+        // Servers may answer with absolute URLs or absolute paths.
+        let href = match Url::parse(&href) {
+            Ok(absolute) => decode(absolute.path()),
+            Err(_) => decode(&href),
+        };
+        let path = match href.trim_end_matches('/').strip_prefix(root) {
+            Some("") => String::new(),
+            Some(path) if path.starts_with('/') => path[1..].to_owned(),
+            _ => continue,
+        };
+        let mut resource = Resource {
+            path,
+            is_collection: false,
+            size: 0,
+            modified: None,
+            etag: None,
+            checksum: None,
+        };
+        for propstat in children(response, "propstat") {
+            if !is_ok(propstat) {
+                continue;
+            }
+            if let Some(prop) = propstat.get_child(("prop", DAV_NS)) {
+                read_props(prop, &mut resource);
+            }
+        }
+        resources.push(resource);
+    }
+    Ok(resources)
+}
+
+/// Tells whether every property in a multistatus body has a `200` status,
+/// as in the answer to a successful PROPPATCH.
+pub fn all_ok<R: Read>(body: R) -> Result<bool> {
+    let multistatus = Element::parse(body)?;
+    let all_ok = children(&multistatus, "response")
+        .flat_map(|response| children(response, "propstat"))
+        .all(is_ok);
+    Ok(all_ok)
+}
+
+/// Iterates over the child elements of `parent` named `name` in the DAV namespace.
+fn children<'a>(parent: &'a Element, name: &'a str) -> impl Iterator<Item = &'a Element> {
+    parent
+        .children
+        .iter()
+        .filter_map(|node| node.as_element())
+        .filter(move |e| e.name == name && e.namespace.as_deref() == Some(DAV_NS))
+}
+
+fn is_ok(propstat: &Element) -> bool {
+    propstat
+        .get_child(("status", DAV_NS))
+        .and_then(|e| e.get_text())
+        .map(|status| status.split_whitespace().nth(1) == Some("200"))
+        .unwrap_or(true)
+}
+
+fn read_props(prop: &Element, resource: &mut Resource) {
+    for property in prop.children.iter().filter_map(|n| n.as_element()) {
+        if property.namespace.as_deref() == Some(OC_NS) && property.name == "checksums" {
+            resource.checksum = property
+                .get_child(("checksum", OC_NS))
+                .and_then(|e| e.get_text())
+                .and_then(|checksums| sha1_checksum(&checksums));
+            continue;
+        }
+        if property.namespace.as_deref() != Some(DAV_NS) {
+            continue;
+        }
+        let text = property.get_text();
+        let text = text.as_deref().map(str::trim);
+        match property.name.as_str() {
+            "resourcetype" => {
+                resource.is_collection = property.get_child(("collection", DAV_NS)).is_some()
+            }
+            "getcontentlength" => {
+                if let Some(size) = text.and_then(|t| t.parse().ok()) {
+                    resource.size = size;
+                }
+            }
+            "getlastmodified" => {
+                resource.modified = text.and_then(|t| httpdate::parse_http_date(t).ok())
+            }
+            "getetag" => resource.etag = text.filter(|t| !t.is_empty()).map(str::to_owned),
+            _ => {}
+        }
+    }
+}
+
+/// Decodes a percent-encoded URL path.
+pub fn decode(path: &str) -> String {
+    percent_decode_str(path).decode_utf8_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A multistatus body made of the given `<D:response>` elements.
+    fn multistatus(responses: &[String]) -> String {
+        format!(
+            r#"<?xml version="1.0"?><D:multistatus xmlns:D="DAV:" xmlns:oc="http://owncloud.org/ns">{}</D:multistatus>"#,
+            responses.concat()
+        )
+    }
+
+    fn response(href: &str, propstats: &[(&str, &str)]) -> String {
+        let propstats: String = propstats
+            .iter()
+            .map(|(status, props)| {
+                format!(
+                    "<D:propstat><D:prop>{}</D:prop><D:status>HTTP/1.1 {}</D:status></D:propstat>",
+                    props, status
+                )
+            })
+            .collect();
+        format!(
+            "<D:response><D:href>{}</D:href>{}</D:response>",
+            href, propstats
+        )
+    }
+
+    fn paths(body: &str, url: &str) -> Vec<String> {
+        let url = Url::parse(url).unwrap();
+        parse(body.as_bytes(), &url)
+            .unwrap()
+            .into_iter()
+            .map(|resource| resource.path)
+            .collect()
+    }
+
+    const COLLECTION: &str = "<D:resourcetype><D:collection/></D:resourcetype>";
+
+    #[test]
+    fn hrefs_may_be_urls_or_paths() {
+        let body = multistatus(&[
+            response("http://example.com/dav/dir/", &[("200 OK", COLLECTION)]),
+            response("/dav/dir/file", &[("200 OK", "")]),
+            response("http://example.com/dav/dir/sub/", &[("200 OK", COLLECTION)]),
+            response("/dav/directory", &[("200 OK", "")]),
+            response("/other/file", &[("200 OK", "")]),
+        ]);
+        assert_eq!(
+            paths(&body, "http://example.com/dav/dir/"),
+            ["", "file", "sub"]
+        );
+        assert_eq!(
+            paths(&body, "http://example.com/dav/dir"),
+            ["", "file", "sub"]
+        );
+    }
+
+    #[test]
+    fn names_are_percent_decoded() {
+        let body = multistatus(&[
+            response("/dav/my%20dir/", &[("200 OK", COLLECTION)]),
+            response("/dav/my%20dir/caf%C3%A9%25.txt", &[("200 OK", "")]),
+            response("/dav/my dir/a b", &[("200 OK", "")]),
+        ]);
+        assert_eq!(
+            paths(&body, "http://example.com/dav/my%20dir/"),
+            ["", "café%.txt", "a b"]
+        );
+    }
+
+    #[test]
+    fn properties_are_read_from_successful_propstats_only() {
+        let body = multistatus(&[response(
+            "/dav/file",
+            &[
+                (
+                    "200 OK",
+                    r#"<D:resourcetype/><D:getcontentlength>42</D:getcontentlength>
+                    <D:getlastmodified>Sun, 06 Nov 1994 08:49:37 GMT</D:getlastmodified>
+                    <D:getetag>"abc"</D:getetag>"#,
+                ),
+                (
+                    "404 Not Found",
+                    "<D:getcontentlength>7</D:getcontentlength><oc:checksums><oc:checksum>SHA1:ff</oc:checksum></oc:checksums>",
+                ),
+            ],
+        )]);
+        let url = Url::parse("http://example.com/dav/file").unwrap();
+        let resource = &parse(body.as_bytes(), &url).unwrap()[0];
+        assert!(!resource.is_collection);
+        assert_eq!(resource.size, 42);
+        assert_eq!(
+            resource.modified,
+            Some(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(784_111_777))
+        );
+        assert_eq!(resource.etag.as_deref(), Some("\"abc\""));
+        assert_eq!(resource.checksum, None);
+    }
+
+    #[test]
+    fn sha1_checksums_are_read_from_oc_checksums() {
+        let body = multistatus(&[response(
+            "/dav/file",
+            &[(
+                "200 OK",
+                "<oc:checksums><oc:checksum>MD5:aa SHA1:0BEE89B07A248E27C83FC3D5951213C1 ADLER32:bb</oc:checksum></oc:checksums>",
+            )],
+        )]);
+        let url = Url::parse("http://example.com/dav/file").unwrap();
+        let resource = &parse(body.as_bytes(), &url).unwrap()[0];
+        assert_eq!(
+            resource.checksum.as_deref(),
+            Some("0bee89b07a248e27c83fc3d5951213c1")
+        );
+    }
+
+    #[test]
+    fn all_ok_needs_every_propstat_to_succeed() {
+        let ok = multistatus(&[response("/dav/file", &[("200 OK", "<D:getlastmodified/>")])]);
+        assert!(all_ok(ok.as_bytes()).unwrap());
+        let refused = multistatus(&[response(
+            "/dav/file",
+            &[
+                ("200 OK", "<D:getlastmodified/>"),
+                ("403 Forbidden", "<oc:lastmodified/>"),
+            ],
+        )]);
+        assert!(!all_ok(refused.as_bytes()).unwrap());
+        assert!(all_ok("not xml".as_bytes()).is_err());
+    }
+}
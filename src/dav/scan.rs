@@ -0,0 +1,217 @@
+//! Recursive listing of remote collections.
+
+use std::collections::{HashSet, VecDeque};
+
+use rustydav::prelude::Url;
+
+use crate::dav::{as_collection, Client, Resource};
+use crate::error::Result;
+use crate::pool;
+
+impl Client {
+    /// Lists every resource below the collection at `url`, at any depth.
+    ///
+    /// A single `Depth: infinity` PROPFIND is tried first. Many servers refuse
+    /// it with `403 Forbidden` (`propfind-finite-depth`), in which case the
+    /// tree is crawled breadth-first with one `Depth: 1` request per
+    /// collection, `jobs` requests at a time. Returns `None` when the
+    /// collection does not exist.
+    ///
+    /// Resources for which `keep` returns false are left out, along with
+    /// everything below them.
+    pub fn scan(
+        &self,
+        url: &Url,
+        keep: &dyn Fn(&Resource) -> bool,
+        jobs: usize,
+    ) -> Result<Option<Vec<Resource>>> {
+        let url = as_collection(url);
+        match self.propfind(&url, "infinity")? {
+            Depth::Refused => {}
+            Depth::Found(resources) => return Ok(resources.map(|all| prune(all, keep))),
+        }
+
+        let mut resources = match self.list(&url)? {
+            Some(resources) => resources,
+            None => return Ok(None),
+        };
+        resources.retain(|resource| keep(resource));
+        let mut pending: VecDeque<String> = collections(&resources).collect();
+        let list = |path: String| {
+            let mut collection = url.clone();
+            collection
+                .path_segments_mut()
+                .expect("WebDAV URLs always have a path")
+                .pop_if_empty()
+                .extend(path.split('/'))
+                .push("");
+            let children = self.list(&collection);
+            (path, children)
+        };
+        pool::scoped(jobs, list, |pool| -> Result<()> {
+            loop {
+                while let Some(path) = pending.pop_front() {
+                    pool.submit(path);
+                }
+                let (path, children) = match pool.wait() {
+                    Some(listed) => listed,
+                    None => return Ok(()),
+                };
+                // A collection removed since it was listed is simply skipped.
+                let children = children?.unwrap_or_default();
+                let first = resources.len();
+                resources.extend(
+                    children
+                        .into_iter()
+                        .map(|mut child| {
+                            child.path = format!("{}/{}", path, child.path);
+                            child
+                        })
+                        .filter(|child| keep(child)),
+                );
+                pending.extend(collections(&resources[first..]));
+            }
+        })?;
+        Ok(Some(resources))
+    }
+}
+
+/// Drops the requested collection itself, the resources `keep` rejects and
+/// the ones below a rejected collection.
+fn prune(mut resources: Vec<Resource>, keep: &dyn Fn(&Resource) -> bool) -> Vec<Resource> {
+    // Sorting puts every collection before its content.
+    resources.sort_by(|a, b| a.path.cmp(&b.path));
+    let mut rejected = HashSet::new();
+    resources.retain(|resource| {
+        if resource.path.is_empty() {
+            return false;
+        }
+        let mut parents = resource
+            .path
+            .match_indices('/')
+            .map(|(i, _)| &resource.path[..i]);
+        if parents.any(|parent| rejected.contains(parent)) {
+            return false;
+        }
+        if keep(resource) {
+            return true;
+        }
+        if resource.is_collection {
+            rejected.insert(resource.path.clone());
+        }
+        false
+    });
+    resources
+}
+
+/// Outcome of a PROPFIND whose depth the server may refuse.
+pub(super) enum Depth {
+    /// The server does not allow this depth.
+    Refused,
+    /// The listed resources, or `None` if the requested one does not exist.
+    Found(Option<Vec<Resource>>),
+}
+
+fn collections(resources: &[Resource]) -> impl Iterator<Item = String> + '_ {
+    resources
+        .iter()
+        .filter(|resource| resource.is_collection)
+        .map(|resource| resource.path.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dav::server::{multistatus, Reply, Server};
+    use crate::dav::{http_client, Credentials, HttpOptions};
+
+    fn resource(path: &str) -> Resource {
+        Resource {
+            path: path.trim_end_matches('/').to_owned(),
+            is_collection: path.ends_with('/'),
+            size: 0,
+            modified: None,
+            etag: None,
+            checksum: None,
+        }
+    }
+
+    fn scan(server: &Server, keep: &dyn Fn(&Resource) -> bool) -> Option<Vec<String>> {
+        let http = http_client(&HttpOptions::default()).unwrap();
+        let client = Client::new(&http, Credentials::Anonymous);
+        let resources = client.scan(&server.url("dav"), keep, 2).unwrap()?;
+        let mut paths: Vec<String> = resources.into_iter().map(|r| r.path).collect();
+        paths.sort();
+        Some(paths)
+    }
+
+    fn not_skipped(resource: &Resource) -> bool {
+        !resource.path.ends_with("skip")
+    }
+
+    #[test]
+    fn rejected_collections_are_pruned_with_their_content() {
+        let resources = ["", "a/", "a/b", "skip/", "skip/c", "skipped", "x"].map(resource);
+        let kept: Vec<String> = prune(resources.to_vec(), &not_skipped)
+            .into_iter()
+            .map(|resource| resource.path)
+            .collect();
+        assert_eq!(kept, ["a", "a/b", "skipped", "x"]);
+    }
+
+    #[test]
+    fn infinite_depth_lists_everything_at_once() {
+        let server = Server::start(|request| match request.header("Depth") {
+            Some("infinity") => Reply::new(
+                207,
+                &multistatus(&["/dav/", "/dav/a/", "/dav/a/b", "/dav/skip/", "/dav/skip/c"]),
+            ),
+            _ => Reply::new(500, ""),
+        });
+        assert_eq!(scan(&server, &not_skipped).unwrap(), ["a", "a/b"]);
+        assert_eq!(server.requests(), ["PROPFIND /dav/"]);
+    }
+
+    #[test]
+    fn refused_infinite_depth_falls_back_to_depth_one() {
+        let server =
+            Server::start(
+                |request| match (request.header("Depth"), request.path.as_str()) {
+                    (Some("infinity"), _) => Reply::new(403, ""),
+                    (Some("1"), "/dav/") => Reply::new(
+                        207,
+                        &multistatus(&["/dav/", "/dav/a%20b/", "/dav/file", "/dav/skip/"]),
+                    ),
+                    (Some("1"), "/dav/a%20b/") => {
+                        Reply::new(207, &multistatus(&["/dav/a%20b/", "/dav/a%20b/c"]))
+                    }
+                    _ => Reply::new(404, ""),
+                },
+            );
+        assert_eq!(
+            scan(&server, &not_skipped).unwrap(),
+            ["a b", "a b/c", "file"]
+        );
+        let depths: Vec<String> = server
+            .received()
+            .iter()
+            .map(|request| {
+                format!(
+                    "{} {}",
+                    request.header("Depth").unwrap_or_default(),
+                    request.path
+                )
+            })
+            .collect();
+        assert_eq!(depths, ["infinity /dav/", "1 /dav/", "1 /dav/a%20b/"]);
+    }
+
+    #[test]
+    fn missing_collections_are_none() {
+        let server = Server::start(|request| match request.header("Depth") {
+            Some("infinity") => Reply::new(403, ""),
+            _ => Reply::new(404, ""),
+        });
+        assert_eq!(scan(&server, &not_skipped), None);
+    }
+}
@@ -0,0 +1,120 @@
+//! Uploads sent in several requests, so that they can be resumed.
+//!
+//! Nextcloud takes the chunks of a file in an upload collection and assembles
+//! them when `.file` is moved to the destination (chunked upload v2). Apache
+//! and sabre/dav servers instead write a PUT with a `Content-Range` header
+//! into the existing resource.
+
+use std::time::SystemTime;
+
+use rustydav::prelude::{Body, Url};
+
+use super::{as_collection, check, file_headers, mtime_accepted, Client};
+use crate::error::Result;
+
+/// Returns the Nextcloud upload collection of the user owning `url`, when it
+/// is a `…/remote.php/dav/files/<user>/…` URL.
+pub fn uploads_url(url: &Url) -> Option<Url> {
+    let segments: Vec<&str> = url.path_segments()?.collect();
+    let files = segments.windows(4).position(|window| {
+        window[..3] == ["remote.php", "dav", "files"] && !window[3].is_empty()
+    })?;
+    let mut uploads = url.clone();
+    uploads
+        .path_segments_mut()
+        .ok()?
+        .clear()
+        .extend(&segments[..files + 2])
+        .extend(["uploads", segments[files + 3], ""]);
+    Some(uploads)
+}
+
+impl Client {
+    /// Creates the Nextcloud upload collection `upload` for a file that will
+    /// be assembled at `destination`.
+    pub fn start_chunks(&self, upload: &Url, destination: &Url) -> Result<()> {
+        let response = self.send(
+            self.request("MKCOL", upload)
+                .header("Destination", destination.as_str()),
+        )?;
+        check(response, "MKCOL", upload)?;
+        Ok(())
+    }
+
+    /// Lists the numbers and sizes of the chunks already in the upload
+    /// collection, or returns `None` when the server dropped it.
+    pub fn chunks(&self, upload: &Url) -> Result<Option<Vec<(usize, u64)>>> {
+        Ok(self.list(upload)?.map(|resources| {
+            let mut chunks: Vec<(usize, u64)> = resources
+                .into_iter()
+                .filter_map(|resource| Some((resource.path.parse().ok()?, resource.size)))
+                .collect();
+            chunks.sort_unstable();
+            chunks
+        }))
+    }
+
+    /// Uploads chunk `number`, counted from 1, of a file of `total` bytes.
+    pub fn put_chunk(
+        &self,
+        upload: &Url,
+        number: usize,
+        chunk: Vec<u8>,
+        destination: &Url,
+        total: u64,
+    ) -> Result<()> {
+        let url = as_collection(upload)
+            .join(&number.to_string())
+            .expect("a number is a valid relative URL");
+        let response = self.send(
+            self.request("PUT", &url)
+                .header("Content-Type", "application/octet-stream")
+                .header("Destination", destination.as_str())
+                .header("OC-Total-Length", total.to_string())
+                .body(Body::from(chunk)),
+        )?;
+        check(response, "PUT", &url)?;
+        Ok(())
+    }
+
+    /// Assembles the chunks of `upload` into `destination`, with the same
+    /// headers as [`Client::put`]. Returns whether the server accepted the
+    /// modification time.
+    pub fn finish_chunks(
+        &self,
+        upload: &Url,
+        destination: &Url,
+        total: u64,
+        checksum: Option<&str>,
+        modified: Option<SystemTime>,
+    ) -> Result<bool> {
+        let url = as_collection(upload)
+            .join(".file")
+            .expect("a name is a valid relative URL");
+        let request = self
+            .request("MOVE", &url)
+            .header("Destination", destination.as_str())
+            .header("Overwrite", "T")
+            .header("OC-Total-Length", total.to_string());
+        let request = file_headers(request, checksum, modified);
+        let response = check(self.send(request)?, "MOVE", &url)?;
+        Ok(mtime_accepted(&response))
+    }
+
+    /// Writes `chunk` at `offset` in the resource at `url`, which will be
+    /// `total` bytes long.
+    pub fn put_range(&self, url: &Url, offset: u64, chunk: Vec<u8>, total: u64) -> Result<()> {
+        let end = offset + chunk.len() as u64 - 1;
+        let response = self.send(
+            self.request("PUT", url)
+                .header("Content-Type", "application/octet-stream")
+                .header(
+                    "Content-Range",
+                    format!("bytes {}-{}/{}", offset, end, total),
+                )
+                .body(Body::from(chunk)),
+        )?;
+        check(response, "PUT", url)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,91 @@
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Result type used throughout davsync.
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Everything that can make a synchronization fail.
+#[derive(Debug)]
+pub enum Error {
+    /// A local filesystem operation failed, on `path` when it is known.
+    Io {
+        path: Option<PathBuf>,
+        source: io::Error,
+    },
+    /// The HTTP request could not be sent or its response could not be read.
+    Http(rustydav::prelude::Error),
+    /// The server answered with an unexpected status code.
+    Status {
+        method: &'static str,
+        url: String,
+        status: u16,
+    },
+    /// The server answered with a body that is not valid WebDAV XML.
+    Xml(xmltree::ParseError),
+    /// A file was not received whole, or its content is not the expected one.
+    Transfer(String),
+    /// The command line arguments do not describe a valid synchronization.
+    Usage(String),
+    /// The synchronization was stopped before changing anything.
+    Aborted(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io {
+                path: Some(path),
+                source,
+            } => write!(f, "'{}': {}", path.display(), source),
+            Error::Io { path: None, source } => write!(f, "{}", source),
+            Error::Http(e) => write!(f, "{}", e),
+            Error::Status {
+                method,
+                url,
+                status,
+            } => write!(f, "{} '{}' failed with status {}", method, url, status),
+            Error::Xml(e) => write!(f, "invalid WebDAV response: {}", e),
+            Error::Transfer(msg) => write!(f, "{}", msg),
+            Error::Usage(msg) => write!(f, "{}", msg),
+            Error::Aborted(msg) => write!(f, "aborted: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io {
+            path: None,
+            source: e,
+        }
+    }
+}
+
+/// Names the file an I/O error is about.
+pub(crate) trait AtPath<T> {
+    fn at(self, path: &Path) -> Result<T>;
+}
+
+impl<T> AtPath<T> for io::Result<T> {
+    fn at(self, path: &Path) -> Result<T> {
+        self.map_err(|source| Error::Io {
+            path: Some(path.to_owned()),
+            source,
+        })
+    }
+}
+
+impl From<rustydav::prelude::Error> for Error {
+    fn from(e: rustydav::prelude::Error) -> Self {
+        Error::Http(e)
+    }
+}
+
+impl From<xmltree::ParseError> for Error {
+    fn from(e: xmltree::ParseError) -> Self {
+        Error::Xml(e)
+    }
+}
@@ -0,0 +1,202 @@
+//! Include and exclude rules with rsync-compatible patterns.
+//!
+//! Rules are evaluated in the order they were given and the first matching
+//! rule decides whether an entry is included. Entries matching no rule are
+//! included. Patterns use the rsync syntax:
+//!
+//! - `*` matches anything but a slash, `**` matches anything, `?` matches
+//!   one character but a slash, `[...]` matches a character class and a
+//!   backslash makes the next character literal;
+//! - a leading `/` anchors the pattern to the root of the synchronization,
+//!   otherwise it matches the end of the path, so a pattern without slashes
+//!   matches a file or directory name anywhere;
+//! - a trailing `/` only matches directories.
+//!
+//! Excluding a directory excludes its whole content: walkers do not descend
+//! into it.
+
+use std::fs;
+use std::path::Path;
+
+use regex::Regex;
+
+use crate::error::{Error, Result};
+
+/// An ordered list of include and exclude rules.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    include: bool,
+    dir_only: bool,
+    regex: Regex,
+}
+
+impl Filter {
+    /// Appends an include or exclude rule.
+    pub fn add(&mut self, include: bool, pattern: &str) -> Result<()> {
+        let dir_only = pattern.len() > 1 && pattern.ends_with('/');
+        let pattern = pattern.trim_end_matches('/');
+        let (anchored, pattern) = match pattern.strip_prefix('/') {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern),
+        };
+        let prefix = if anchored { "^" } else { "^(?:.*/)?" };
+        let regex = Regex::new(&format!("{}{}$", prefix, glob_to_regex(pattern)))
+            .map_err(|e| Error::Usage(format!("invalid pattern '{}': {}", pattern, e)))?;
+        self.rules.push(Rule {
+            include,
+            dir_only,
+            regex,
+        });
+        Ok(())
+    }
+
+    /// Appends a rule for each line of the file at `path`.
+    ///
+    /// Empty lines and lines starting with `#` or `;` are ignored.
+    pub fn add_file(&mut self, include: bool, path: &Path) -> Result<()> {
+        let content = fs::read_to_string(path).map_err(|e| {
+            Error::Usage(format!(
+                "cannot read rules from '{}': {}",
+                path.display(),
+                e
+            ))
+        })?;
+        for line in content.lines() {
+            if line.trim().is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            self.add(include, line)?;
+        }
+        Ok(())
+    }
+
+    /// Tells whether the entry at `path` is excluded by the rules.
+    ///
+    /// Only the entry itself is matched, the walkers take care of not
+    /// descending into excluded directories.
+    pub fn is_excluded(&self, path: &str, is_dir: bool) -> bool {
+        self.matches(path, is_dir) == Some(false)
+    }
+
+    /// Returns whether the first rule matching the entry at `path` includes
+    /// it, or `None` when no rule matches.
+    pub fn matches(&self, path: &str, is_dir: bool) -> Option<bool> {
+        self.rules
+            .iter()
+            .find(|rule| (is_dir || !rule.dir_only) && rule.regex.is_match(path))
+            .map(|rule| rule.include)
+    }
+}
+
+/// Translates an rsync glob into a regular expression.
+pub(crate) fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::new();
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                regex.push_str(".*");
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            // A backslash makes the next character literal.
+            '\\' => {
+                if let Some(c) = chars.next() {
+                    regex.push_str(&regex::escape(&c.to_string()));
+                }
+            }
+            '[' => {
+                let negated = chars.peek() == Some(&'!');
+                let mut raw = String::new();
+                let mut class = String::from("[");
+                if negated {
+                    chars.next();
+                    class.push('^');
+                }
+                let mut closed = false;
+                for c in chars.by_ref() {
+                    // A `]` right after the opening bracket is part of the class.
+                    if c == ']' && !raw.is_empty() {
+                        closed = true;
+                        break;
+                    }
+                    raw.push(c);
+                    if c == '\\' || c == '[' {
+                        class.push('\\');
+                    }
+                    class.push(c);
+                }
+                if closed {
+                    regex.push_str(&class);
+                    regex.push(']');
+                } else {
+                    // Not a class after all, match the characters literally.
+                    let bang = if negated { "!" } else { "" };
+                    regex.push_str(&regex::escape(&format!("[{}{}", bang, raw)));
+                }
+            }
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(rules: &[(bool, &str)]) -> Filter {
+        let mut filter = Filter::default();
+        for &(include, pattern) in rules {
+            filter.add(include, pattern).unwrap();
+        }
+        filter
+    }
+
+    #[test]
+    fn globs_become_regexes() {
+        assert_eq!(glob_to_regex("*.txt"), r"[^/]*\.txt");
+        assert_eq!(glob_to_regex("a/**"), "a/.*");
+        assert_eq!(glob_to_regex("?"), "[^/]");
+        assert_eq!(glob_to_regex(r"\*"), r"\*");
+        assert_eq!(glob_to_regex("[!a-c]"), "[^a-c]");
+        assert_eq!(glob_to_regex("[]a]"), "[]a]");
+        // An unclosed bracket is literal.
+        assert_eq!(glob_to_regex("[ab"), r"\[ab");
+    }
+
+    #[test]
+    fn unanchored_patterns_match_names_anywhere() {
+        let filter = filter(&[(false, "*.tmp")]);
+        assert!(filter.is_excluded("a.tmp", false));
+        assert!(filter.is_excluded("dir/sub/a.tmp", false));
+        assert!(!filter.is_excluded("a.tmp.txt", false));
+        assert!(!filter.is_excluded("a.tmp/file", false));
+    }
+
+    #[test]
+    fn anchored_and_directory_patterns() {
+        let filter = filter(&[(false, "/build/"), (false, "/docs/*.md")]);
+        assert!(filter.is_excluded("build", true));
+        assert!(!filter.is_excluded("build", false));
+        assert!(!filter.is_excluded("src/build", true));
+        assert!(filter.is_excluded("docs/a.md", false));
+        assert!(!filter.is_excluded("docs/sub/a.md", false));
+        assert!(!filter.is_excluded("other/docs/a.md", false));
+    }
+
+    #[test]
+    fn the_first_matching_rule_wins() {
+        let filter = filter(&[(true, "keep.log"), (false, "*.log"), (false, "**")]);
+        assert_eq!(filter.matches("keep.log", false), Some(true));
+        assert_eq!(filter.matches("dir/other.log", false), Some(false));
+        assert!(filter.is_excluded("anything", true));
+        assert_eq!(Filter::default().matches("anything", false), None);
+    }
+}
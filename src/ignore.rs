@@ -0,0 +1,302 @@
+//! Per-directory ignore files, like `.davsyncignore`, with gitignore
+//! semantics.
+//!
+//! The rules of an ignore file apply to the directory it is in and to
+//! everything below. Within a file the last matching rule wins, and the rules
+//! of a subdirectory override the ones of its parents:
+//!
+//! - a pattern containing a slash, other than a trailing one, is relative to
+//!   the directory of the ignore file, otherwise it matches a name at any
+//!   depth;
+//! - `*`, `?` and `[...]` do not match slashes, a leading `**/` matches any
+//!   number of directories, a trailing `/**` everything inside and `/**/`
+//!   zero or more directories;
+//! - a trailing `/` only matches directories;
+//! - a leading `!` negates the pattern, re-including what a previous rule
+//!   ignored. As with git, nothing inside an ignored directory can be
+//!   re-included.
+
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
+
+use regex::Regex;
+
+use crate::backend::{ancestors, join, Backend, Entry};
+use crate::error::{Error, Result};
+use crate::filter::glob_to_regex;
+
+/// Name of the ignore files davsync always honours.
+pub const IGNORE_FILE: &str = ".davsyncignore";
+
+/// The rules of the ignore files of a tree, by directory.
+#[derive(Debug, Default)]
+pub struct Ignores {
+    dirs: HashMap<String, Vec<Rule>>,
+}
+
+#[derive(Debug)]
+struct Rule {
+    negated: bool,
+    dir_only: bool,
+    regex: Regex,
+}
+
+impl Ignores {
+    /// Reads the ignore files named `names` among `entries`, the entries of a
+    /// tree of `backend` sorted by path, and removes the ignored entries.
+    ///
+    /// In a directory holding several ignore files, the rules of the later
+    /// names override the earlier ones. Entries for which `decided` returns
+    /// true are kept whatever the ignore files say.
+    pub fn load(
+        backend: &dyn Backend,
+        names: &[String],
+        entries: &mut Vec<Entry>,
+        decided: &dyn Fn(&Entry) -> bool,
+    ) -> Result<Ignores> {
+        let files: HashSet<String> = entries
+            .iter()
+            .filter(|entry| !entry.is_dir)
+            .map(|entry| entry.path.clone())
+            .collect();
+        let mut ignores = Ignores::default();
+        let mut loaded = HashSet::new();
+        let mut error = None;
+        retain_tree(entries, |entry| {
+            let dir = parent(&entry.path);
+            if loaded.insert(dir.to_owned()) {
+                for name in names {
+                    let path = join(dir, name);
+                    if !files.contains(&path) {
+                        continue;
+                    }
+                    if let Err(e) = ignores.read(backend, dir, &path) {
+                        error.get_or_insert(e);
+                    }
+                }
+            }
+            decided(entry) || !ignores.is_ignored(&entry.path, entry.is_dir)
+        });
+        match error {
+            Some(e) => Err(e),
+            None => Ok(ignores),
+        }
+    }
+
+    /// Removes from `entries`, sorted by path, the ones these rules ignore
+    /// along with their content, except the ones for which `decided` returns
+    /// true.
+    pub fn prune(&self, entries: &mut Vec<Entry>, decided: &dyn Fn(&Entry) -> bool) {
+        if !self.dirs.is_empty() {
+            retain_tree(entries, |entry| {
+                decided(entry) || !self.is_ignored(&entry.path, entry.is_dir)
+            });
+        }
+    }
+
+    /// Tells whether the rules ignore the entry at `path` itself.
+    pub fn is_ignored(&self, path: &str, is_dir: bool) -> bool {
+        let mut ignored = false;
+        // From the root down, so that deeper rules override.
+        let mut dirs: Vec<&str> = ancestors(path).collect();
+        dirs.push("");
+        for dir in dirs.into_iter().rev() {
+            let rules = match self.dirs.get(dir) {
+                Some(rules) => rules,
+                None => continue,
+            };
+            let relative = if dir.is_empty() {
+                path
+            } else {
+                &path[dir.len() + 1..]
+            };
+            if let Some(rule) = rules
+                .iter()
+                .rev()
+                .find(|rule| (is_dir || !rule.dir_only) && rule.regex.is_match(relative))
+            {
+                ignored = !rule.negated;
+            }
+        }
+        ignored
+    }
+
+    /// Appends the rules of the ignore file at `path`, in the directory `dir`.
+    fn read(&mut self, backend: &dyn Backend, dir: &str, path: &str) -> Result<()> {
+        let mut content = Vec::new();
+        backend.read(path)?.read_to_end(&mut content)?;
+        let rules = self.dirs.entry(dir.to_owned()).or_default();
+        for line in String::from_utf8_lossy(&content).lines() {
+            if let Some(rule) = parse(line).map_err(|e| {
+                Error::Usage(format!(
+                    "invalid pattern in '{}': {}",
+                    backend.location(path),
+                    e
+                ))
+            })? {
+                rules.push(rule);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parses a line of an ignore file, returning `None` for blank lines and
+/// comments.
+fn parse(line: &str) -> std::result::Result<Option<Rule>, regex::Error> {
+    // Trailing spaces are ignored unless escaped with a backslash.
+    let mut pattern = line.trim_end_matches(' ');
+    if pattern.ends_with('\\') && pattern.len() < line.len() {
+        pattern = &line[..pattern.len() + 1];
+    }
+    if pattern.is_empty() || pattern.starts_with('#') {
+        return Ok(None);
+    }
+    let (negated, pattern) = match pattern.strip_prefix('!') {
+        Some(pattern) => (true, pattern),
+        None => (false, pattern),
+    };
+    let (dir_only, pattern) = match pattern.strip_suffix('/') {
+        Some(pattern) => (true, pattern),
+        None => (false, pattern),
+    };
+    let anchored = pattern.contains('/');
+    let pattern = pattern.strip_prefix('/').unwrap_or(pattern);
+    if pattern.is_empty() {
+        return Ok(None);
+    }
+
+    let mut regex = String::from(if anchored { "^" } else { "^(?:.*/)?" });
+    let segments: Vec<&str> = pattern.split('/').collect();
+    for (i, segment) in segments.iter().enumerate() {
+        let last = i + 1 == segments.len();
+        if *segment == "**" {
+            regex.push_str(if last { ".*" } else { "(?:.*/)?" });
+        } else {
+            regex.push_str(&glob_to_regex(segment));
+            if !last {
+                regex.push('/');
+            }
+        }
+    }
+    regex.push('$');
+    Ok(Some(Rule {
+        negated,
+        dir_only,
+        regex: Regex::new(&regex)?,
+    }))
+}
+
+/// Keeps the entries, sorted by path, for which `keep` returns true, dropping
+/// the content of the directories it rejects without asking.
+fn retain_tree(entries: &mut Vec<Entry>, mut keep: impl FnMut(&Entry) -> bool) {
+    let mut rejected = HashSet::new();
+    entries.retain(|entry| {
+        if ancestors(&entry.path).any(|dir| rejected.contains(dir)) {
+            return false;
+        }
+        if keep(entry) {
+            return true;
+        }
+        if entry.is_dir {
+            rejected.insert(entry.path.clone());
+        }
+        false
+    });
+}
+
+fn parent(path: &str) -> &str {
+    ancestors(path).next().unwrap_or("")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(line: &str, path: &str, is_dir: bool) -> bool {
+        let rule = parse(line).unwrap().unwrap();
+        (is_dir || !rule.dir_only) && rule.regex.is_match(path)
+    }
+
+    #[test]
+    fn blank_lines_and_comments_are_skipped() {
+        for line in ["", "   ", "# comment", "/", "!"] {
+            assert!(parse(line).unwrap().is_none(), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn patterns_without_slashes_match_at_any_depth() {
+        assert!(matches("*.o", "main.o", false));
+        assert!(matches("*.o", "src/main.o", false));
+        assert!(!matches("*.o", "main.orig", false));
+    }
+
+    #[test]
+    fn patterns_with_slashes_are_relative() {
+        assert!(matches("/target", "target", true));
+        assert!(!matches("/target", "sub/target", true));
+        assert!(matches("doc/*.html", "doc/index.html", false));
+        assert!(!matches("doc/*.html", "sub/doc/index.html", false));
+        assert!(!matches("doc/*.html", "doc/api/index.html", false));
+    }
+
+    #[test]
+    fn double_stars() {
+        assert!(matches("**/logs", "logs", true));
+        assert!(matches("**/logs", "a/b/logs", true));
+        assert!(matches("build/**", "build/a/b", false));
+        assert!(!matches("build/**", "build", true));
+        assert!(matches("a/**/b", "a/b", false));
+        assert!(matches("a/**/b", "a/x/y/b", false));
+    }
+
+    #[test]
+    fn negations_directories_and_spaces() {
+        let rule = parse("!keep/").unwrap().unwrap();
+        assert!(rule.negated && rule.dir_only);
+        assert!(matches("cache/", "cache", true));
+        assert!(!matches("cache/", "cache", false));
+        // Trailing spaces are dropped unless escaped.
+        assert!(matches("name  ", "name", false));
+        assert!(matches(r"name\ ", "name ", false));
+    }
+
+    #[test]
+    fn deeper_rules_override_and_ignored_directories_stay_ignored() {
+        let mut ignores = Ignores::default();
+        let rules = |lines: &[&str]| -> Vec<Rule> {
+            lines
+                .iter()
+                .filter_map(|line| parse(line).unwrap())
+                .collect()
+        };
+        ignores
+            .dirs
+            .insert(String::new(), rules(&["*.log", "/out/"]));
+        ignores.dirs.insert("src".to_owned(), rules(&["!keep.log"]));
+        assert!(ignores.is_ignored("a.log", false));
+        assert!(ignores.is_ignored("src/a.log", false));
+        assert!(!ignores.is_ignored("src/keep.log", false));
+        assert!(ignores.is_ignored("out", true));
+
+        let entry = |path: &str, is_dir: bool| Entry {
+            path: path.to_owned(),
+            is_dir,
+            size: 0,
+            modified: None,
+            etag: None,
+            checksum: None,
+        };
+        let mut entries = vec![
+            entry("a.txt", false),
+            entry("out", true),
+            entry("out/keep.log", false),
+            entry("src", true),
+            entry("src/keep.log", false),
+        ];
+        ignores.prune(&mut entries, &|_| false);
+        let paths: Vec<&str> = entries.iter().map(|entry| entry.path.as_str()).collect();
+        assert_eq!(paths, ["a.txt", "src", "src/keep.log"]);
+    }
+}
@@ -0,0 +1,15 @@
+//! Synchronization of local directories and WebDAV collections.
+
+pub mod backend;
+pub mod bisync;
+pub mod compare;
+pub mod credentials;
+pub mod dav;
+pub mod endpoint;
+pub mod error;
+pub mod filter;
+pub mod ignore;
+pub mod netrc;
+pub mod pool;
+pub mod state;
+pub mod sync;
@@ -1,22 +1,258 @@
-use clap::{load_yaml, App};
-use cli_toolbox::{debugln, reportln};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Duration;
+
+use clap::{load_yaml, App, ArgMatches};
+use cli_toolbox::reportln;
 use verbosity::Verbosity;
 
+use davsync::backend;
+use davsync::bisync;
+use davsync::credentials;
+use davsync::dav::{self, Credentials, HttpOptions, TokenSource};
+use davsync::endpoint::{self, Endpoint};
+use davsync::error::{Error, Result};
+use davsync::filter::Filter;
+use davsync::ignore::IGNORE_FILE;
+use davsync::sync::{self, DeleteTiming};
+
 fn main() {
     let yaml = load_yaml!("cli.yml");
     let matches = App::from_yaml(yaml).get_matches();
 
-    // Get command line arguments
-    let source_path = matches.value_of("source").unwrap();
-    let target_path = matches.value_of("target").unwrap();
     match matches.occurrences_of("verbose") {
+        // A dry run is useless without its report.
+        0 if matches.is_present("dry-run") => Verbosity::Terse.set_as_global(),
         0 => Verbosity::Quite.set_as_global(),
         1 => Verbosity::Terse.set_as_global(),
-        2 | _ => Verbosity::Verbose.set_as_global(),
+        _ => Verbosity::Verbose.set_as_global(),
     };
 
+    if let Err(e) = run(&matches) {
+        eprintln!("davsync: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run(matches: &ArgMatches) -> Result<()> {
+    // Get command line arguments
+    let source_path = matches.value_of("source").unwrap();
+    let target_path = matches.value_of("target").unwrap();
+    let options = options(matches)?;
+
+    let source = Endpoint::parse(source_path)?;
+    let target = Endpoint::parse(target_path)?;
+    endpoint::check_disjoint(&source, &target)?;
     reportln!(
         @terse "Synchronizing…";
-        @verbose "Sync from '{}' to '{}' with verbosity'", source_path, target_path;
-    )
+        @verbose "Sync from '{}' to '{}'", source, target;
+    );
+
+    // A single HTTP client keeps the connections open for the whole run.
+    let settings = backend::Settings {
+        http: dav::http_client(&http_options(matches)?)?,
+        jobs: options.jobs,
+        chunk_size: chunk_size(matches)?,
+        inplace: matches.is_present("inplace"),
+        delta: matches.is_present("delta"),
+    };
+    let credentials = credentials_options(matches);
+    let source = backend::open(&source, login(&source, &credentials)?, &settings);
+    let target = backend::open(&target, login(&target, &credentials)?, &settings);
+    let bidirectional = matches.is_present("bidirectional");
+    let stats = if bidirectional {
+        bisync::run(&*source, &*target, &options)?
+    } else {
+        sync::run(&*source, &*target, &options)?
+    };
+    let conflicts = if bidirectional {
+        format!(", {} conflicts", stats.conflicts)
+    } else {
+        String::new()
+    };
+    let dry_run = if options.dry_run { " (dry run)" } else { "" };
+    reportln!(
+        @terse "{} transferred, {} unchanged, {} directories created, {} deleted{}{}",
+        stats.transferred, stats.unchanged, stats.directories, stats.deleted, conflicts, dry_run
+    );
+    Ok(())
+}
+
+/// Builds the synchronization settings from the command line options.
+fn options(matches: &ArgMatches) -> Result<sync::Options> {
+    let mut options = sync::Options {
+        jobs: 1,
+        checksum: matches.is_present("checksum"),
+        dry_run: matches.is_present("dry-run"),
+        delete: delete_timing(matches),
+        delete_excluded: matches.is_present("delete-excluded"),
+        ..Default::default()
+    };
+    if let Some(window) = matches.value_of("modify-window") {
+        let seconds = window.parse().map_err(|_| {
+            Error::Usage(format!(
+                "invalid --modify-window '{}': expected seconds",
+                window
+            ))
+        })?;
+        options.modify_window = Duration::from_secs(seconds);
+    }
+    if let Some(max) = matches.value_of("max-delete") {
+        options.max_delete = Some(max.parse().map_err(|_| {
+            Error::Usage(format!("invalid --max-delete '{}': expected a number", max))
+        })?);
+    }
+    if let Some(max) = matches.value_of("max-delete-percent") {
+        let invalid = || {
+            Error::Usage(format!(
+                "invalid --max-delete-percent '{}': expected a percentage",
+                max
+            ))
+        };
+        let percent: f64 = max.trim_end_matches('%').parse().map_err(|_| invalid())?;
+        if !(0.0..=100.0).contains(&percent) {
+            return Err(invalid());
+        }
+        options.max_delete_percent = Some(percent);
+    }
+    if let Some(jobs) = matches.value_of("jobs") {
+        options.jobs = jobs.parse().ok().filter(|&jobs| jobs > 0).ok_or_else(|| {
+            Error::Usage(format!(
+                "invalid --jobs '{}': expected a positive number",
+                jobs
+            ))
+        })?;
+    }
+    if let Some(policy) = matches.value_of("conflict") {
+        options.conflict = policy.parse()?;
+    }
+    options.filter = filter(matches)?;
+    if matches.is_present("respect-gitignore") {
+        options.ignore_files.push(".gitignore".to_owned());
+    }
+    options.ignore_files.push(IGNORE_FILE.to_owned());
+    Ok(options)
+}
+
+/// Builds the include and exclude rules, keeping their command line order
+/// across the four options.
+fn filter(matches: &ArgMatches) -> Result<Filter> {
+    let mut rules = Vec::new();
+    for (name, include, from_file) in [
+        ("exclude", false, false),
+        ("include", true, false),
+        ("exclude-from", false, true),
+        ("include-from", true, true),
+    ] {
+        if let (Some(indices), Some(values)) = (matches.indices_of(name), matches.values_of(name)) {
+            rules.extend(
+                indices
+                    .zip(values)
+                    .map(|(index, value)| (index, include, from_file, value)),
+            );
+        }
+    }
+    rules.sort_by_key(|&(index, ..)| index);
+
+    let mut filter = Filter::default();
+    for (_, include, from_file, value) in rules {
+        if from_file {
+            filter.add_file(include, Path::new(value))?;
+        } else {
+            filter.add(include, value)?;
+        }
+    }
+    Ok(filter)
+}
+
+/// Builds the HTTP connection settings from the command line options.
+fn http_options(matches: &ArgMatches) -> Result<HttpOptions> {
+    let mut options = HttpOptions::default();
+    if let Some(size) = matches.value_of("pool-size") {
+        options.pool_size = Some(size.parse().map_err(|_| {
+            Error::Usage(format!("invalid --pool-size '{}': expected a number", size))
+        })?);
+    }
+    if let Some(timeout) = matches.value_of("idle-timeout") {
+        let seconds = timeout.parse().map_err(|_| {
+            Error::Usage(format!(
+                "invalid --idle-timeout '{}': expected seconds",
+                timeout
+            ))
+        })?;
+        options.idle_timeout = Some(Duration::from_secs(seconds));
+    }
+    Ok(options)
+}
+
+/// Reads the credentials options.
+fn credentials_options(matches: &ArgMatches) -> credentials::Options {
+    let bearer = if let Some(token) = matches.value_of("bearer-token") {
+        Some(TokenSource::Fixed(token.to_owned()))
+    } else if let Some(file) = matches.value_of("bearer-token-file") {
+        Some(TokenSource::File(PathBuf::from(file)))
+    } else {
+        matches
+            .value_of("bearer-token-command")
+            .map(|command| TokenSource::Command(command.to_owned()))
+    };
+    credentials::Options {
+        user: matches.value_of("user").map(str::to_owned),
+        password_file: matches.value_of("password-file").map(PathBuf::from),
+        bearer,
+        netrc_file: matches.value_of("netrc-file").map(PathBuf::from),
+    }
+}
+
+/// Finds the credentials of `endpoint`, when it is remote.
+fn login(endpoint: &Endpoint, options: &credentials::Options) -> Result<Credentials> {
+    match endpoint {
+        Endpoint::Remote(remote) => credentials::resolve(remote, options),
+        Endpoint::Local(_) => Ok(Credentials::Anonymous),
+    }
+}
+
+/// Reads `--chunk-size`, a number of bytes with an optional `K`, `M` or `G`
+/// suffix.
+fn chunk_size(matches: &ArgMatches) -> Result<u64> {
+    let size = match matches.value_of("chunk-size") {
+        Some(size) => size,
+        None => return Ok(backend::DEFAULT_CHUNK_SIZE),
+    };
+    let (number, unit) = match size.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((i, _)) => size.split_at(i),
+        None => (size, ""),
+    };
+    let multiplier: u64 = match unit.to_ascii_uppercase().as_str() {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        _ => 0,
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .filter(|&bytes| bytes > 0)
+        .ok_or_else(|| {
+            Error::Usage(format!(
+                "invalid --chunk-size '{}': expected a size like 10M",
+                size
+            ))
+        })
+}
+
+/// Returns when extraneous target entries are deleted. Like in rsync, every
+/// `--delete-*` option implies `--delete`, which deletes during the transfer.
+fn delete_timing(matches: &ArgMatches) -> Option<DeleteTiming> {
+    if matches.is_present("delete-before") {
+        Some(DeleteTiming::Before)
+    } else if matches.is_present("delete-after") {
+        Some(DeleteTiming::After)
+    } else if matches.is_present("delete") || matches.is_present("delete-excluded") {
+        Some(DeleteTiming::During)
+    } else {
+        None
+    }
 }
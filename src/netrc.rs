@@ -0,0 +1,204 @@
+//! Credentials from `.netrc` files, as used by ftp, curl and rclone.
+//!
+//! A netrc file is a list of whitespace-separated tokens: `machine <host>`
+//! starts the entry of a host and `default` the entry of all other hosts,
+//! followed by `login <user>`, `password <password>` and `account <account>`.
+//! `macdef <name>` defines a macro lasting until the next empty line, which
+//! is skipped. Like curl, values may be double-quoted to hold spaces.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::error::{Error, Result};
+
+/// The entries of a netrc file.
+#[derive(Debug, Default)]
+pub struct Netrc {
+    machines: Vec<Machine>,
+}
+
+/// The entry of a host, or the default entry when `host` is `None`.
+#[derive(Debug, Default)]
+pub struct Machine {
+    pub host: Option<String>,
+    pub login: Option<String>,
+    pub password: Option<String>,
+}
+
+impl Netrc {
+    /// Reads the netrc file at `path`. A missing file is empty unless
+    /// `required`.
+    pub fn load(path: &Path, required: bool) -> Result<Netrc> {
+        match fs::read_to_string(path) {
+            Ok(content) => Ok(Netrc::parse(&content)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !required => Ok(Netrc::default()),
+            Err(e) => Err(Error::Usage(format!(
+                "cannot read netrc file '{}': {}",
+                path.display(),
+                e
+            ))),
+        }
+    }
+
+    fn parse(content: &str) -> Netrc {
+        let mut netrc = Netrc::default();
+        let mut tokens = Tokens { rest: content };
+        while let Some(token) = tokens.next_token() {
+            match token.as_str() {
+                "machine" => netrc.machines.push(Machine {
+                    host: tokens.next_token(),
+                    ..Default::default()
+                }),
+                "default" => netrc.machines.push(Machine::default()),
+                "login" | "password" | "account" => {
+                    let value = tokens.next_token();
+                    if let Some(machine) = netrc.machines.last_mut() {
+                        match token.as_str() {
+                            "login" => machine.login = value,
+                            "password" => machine.password = value,
+                            _ => {}
+                        }
+                    }
+                }
+                "macdef" => tokens.skip_macro(),
+                _ => {}
+            }
+        }
+        netrc
+    }
+
+    /// Finds the entry of `host`, or the default one, whose login is `user`
+    /// when the user is already known.
+    pub fn find(&self, host: &str, user: Option<&str>) -> Option<&Machine> {
+        let matches = |machine: &&Machine| {
+            user.is_none_or(|user| machine.login.as_deref().is_none_or(|login| login == user))
+        };
+        self.machines
+            .iter()
+            .filter(|machine| {
+                machine
+                    .host
+                    .as_deref()
+                    .is_some_and(|name| name.eq_ignore_ascii_case(host))
+            })
+            .find(matches)
+            .or_else(|| {
+                self.machines
+                    .iter()
+                    .filter(|machine| machine.host.is_none())
+                    .find(matches)
+            })
+    }
+}
+
+/// Splits a netrc file into tokens.
+struct Tokens<'a> {
+    rest: &'a str,
+}
+
+impl Tokens<'_> {
+    fn next_token(&mut self) -> Option<String> {
+        self.rest = self.rest.trim_start();
+        if self.rest.is_empty() {
+            return None;
+        }
+        let mut token = String::new();
+        if let Some(quoted) = self.rest.strip_prefix('"') {
+            let mut chars = quoted.char_indices();
+            self.rest = "";
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    '\\' => token.extend(chars.next().map(|(_, c)| c)),
+                    '"' => {
+                        self.rest = &quoted[i + 1..];
+                        break;
+                    }
+                    c => token.push(c),
+                }
+            }
+        } else {
+            let end = self
+                .rest
+                .find(char::is_whitespace)
+                .unwrap_or(self.rest.len());
+            token.push_str(&self.rest[..end]);
+            self.rest = &self.rest[end..];
+        }
+        Some(token)
+    }
+
+    /// Skips a macro definition: its name, then everything up to an empty
+    /// line.
+    fn skip_macro(&mut self) {
+        self.next_token();
+        self.rest = match self.rest.find("\n\n") {
+            Some(end) => &self.rest[end..],
+            None => "",
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(content: &str) -> Vec<String> {
+        let mut tokens = Tokens { rest: content };
+        std::iter::from_fn(|| tokens.next_token()).collect()
+    }
+
+    #[test]
+    fn tokens_are_split_on_whitespace() {
+        assert_eq!(
+            tokens("machine example.com\n\tlogin alice  password s3cret\n"),
+            [
+                "machine",
+                "example.com",
+                "login",
+                "alice",
+                "password",
+                "s3cret"
+            ]
+        );
+        assert!(tokens(" \n\t").is_empty());
+    }
+
+    #[test]
+    fn quoted_tokens_hold_spaces_and_escapes() {
+        assert_eq!(
+            tokens(r#"password "two words" login "a \"quoted\" \\ name""#),
+            ["password", "two words", "login", r#"a "quoted" \ name"#]
+        );
+        // An unterminated quote runs to the end.
+        assert_eq!(tokens(r#"password "open end"#), ["password", "open end"]);
+    }
+
+    #[test]
+    fn macros_are_skipped_up_to_an_empty_line() {
+        let netrc = Netrc::parse(
+            "macdef init\ncd /pub\nmachine fake login x\n\nmachine example.com login alice password s3cret\n",
+        );
+        let machine = netrc.find("example.com", None).unwrap();
+        assert_eq!(machine.login.as_deref(), Some("alice"));
+        assert!(netrc.find("fake", None).is_none());
+    }
+
+    #[test]
+    fn hosts_come_before_the_default_entry() {
+        let netrc = Netrc::parse(
+            "default login anonymous password guest\n\
+             machine Example.com login alice password a\n\
+             machine example.com login bob password b\n",
+        );
+        let login = |host: &str, user: Option<&str>| {
+            netrc
+                .find(host, user)
+                .and_then(|machine| machine.password.clone())
+        };
+        assert_eq!(login("example.com", None).as_deref(), Some("a"));
+        assert_eq!(login("example.com", Some("bob")).as_deref(), Some("b"));
+        assert_eq!(login("example.com", Some("carol")), None);
+        assert_eq!(login("other.org", None).as_deref(), Some("guest"));
+    }
+}
@@ -0,0 +1,97 @@
+//! A fixed set of worker threads running the same function on many jobs.
+
+use std::collections::VecDeque;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
+use std::thread;
+
+/// Jobs handed to the workers of [`scoped`], and their results.
+pub struct Pool<J, R> {
+    jobs: Sender<J>,
+    results: Receiver<R>,
+    workers: usize,
+    /// Number of jobs submitted whose result was not received yet.
+    busy: usize,
+    /// Results received while waiting for a free worker.
+    finished: VecDeque<R>,
+}
+
+impl<J, R> Pool<J, R> {
+    /// Hands `job` to a worker, first waiting for one to be free.
+    pub fn submit(&mut self, job: J) {
+        while self.busy >= self.workers {
+            let result = self.receive();
+            self.finished.push_back(result);
+        }
+        self.jobs
+            .send(job)
+            .expect("workers run until the pool is dropped");
+        self.busy += 1;
+    }
+
+    /// Returns the results received so far, without waiting.
+    pub fn finished(&mut self) -> impl Iterator<Item = R> + '_ {
+        self.finished.drain(..)
+    }
+
+    /// Returns the result of a job, waiting for one to finish if needed, or
+    /// `None` once every result was returned.
+    pub fn wait(&mut self) -> Option<R> {
+        if let Some(result) = self.finished.pop_front() {
+            return Some(result);
+        }
+        if self.busy == 0 {
+            return None;
+        }
+        Some(self.receive())
+    }
+
+    fn receive(&mut self) -> R {
+        let result = self
+            .results
+            .recv()
+            .expect("workers run until the pool is dropped");
+        self.busy -= 1;
+        result
+    }
+}
+
+/// Runs `body` with a pool of `workers` threads applying `work` to the jobs
+/// it submits. Results come back in the order jobs finish.
+///
+/// Returns once `body` returned and the jobs under way are done.
+pub fn scoped<J, R, T, W, B>(workers: usize, work: W, body: B) -> T
+where
+    J: Send,
+    R: Send,
+    W: Fn(J) -> R + Sync,
+    B: FnOnce(&mut Pool<J, R>) -> T,
+{
+    let workers = workers.max(1);
+    let (jobs, queue) = mpsc::channel::<J>();
+    let queue = Mutex::new(queue);
+    let (sender, results) = mpsc::channel();
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            let (queue, work, sender) = (&queue, &work, sender.clone());
+            scope.spawn(move || loop {
+                // The pool dropping its sender stops the workers.
+                let job = match queue.lock().expect("no worker panicked").recv() {
+                    Ok(job) => job,
+                    Err(_) => break,
+                };
+                if sender.send(work(job)).is_err() {
+                    break;
+                }
+            });
+        }
+        let mut pool = Pool {
+            jobs,
+            results,
+            workers,
+            busy: 0,
+            finished: VecDeque::new(),
+        };
+        body(&mut pool)
+    })
+}
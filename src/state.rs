@@ -0,0 +1,317 @@
+//! Persistent record of the last two-way synchronization of a pair of trees.
+//!
+//! The state of a pair is a text file in `$XDG_STATE_HOME/davsync/`
+//! (`~/.local/state/davsync/` by default) named after a hash of both
+//! locations. After a header line, each line describes a path that was in
+//! sync on both sides:
+//!
+//! ```text
+//! <path> TAB <d|f> TAB <size> TAB <mtime> TAB <etag> TAB <size> TAB <mtime> TAB <etag>
+//! ```
+//!
+//! with the size, modification time (Unix seconds, empty if unknown) and
+//! entity tag (empty if unknown) of each side. Paths and tags are
+//! percent-encoded so that they never contain tabs or line breaks.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+
+use crate::backend::Entry;
+use crate::compare::{self, seconds};
+use crate::endpoint::Endpoint;
+use crate::error::{AtPath, Error, Result};
+
+const HEADER: &str = "davsync-state 1";
+
+/// Characters escaped in the fields of the state file.
+const FIELD: &AsciiSet = &CONTROLS.add(b'%');
+
+/// What was recorded of a file or directory on one side.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub is_dir: bool,
+    pub size: u64,
+    /// Modification time in whole seconds since the Unix epoch.
+    pub modified: Option<u64>,
+    pub etag: Option<String>,
+}
+
+impl Record {
+    /// Records `entry` as it is now.
+    pub fn new(entry: &Entry) -> Self {
+        Record {
+            is_dir: entry.is_dir,
+            size: entry.size,
+            modified: entry.modified.map(seconds),
+            etag: entry.etag.clone(),
+        }
+    }
+
+    /// Record of a directory, whose size and times do not matter.
+    pub fn directory() -> Self {
+        Record {
+            is_dir: true,
+            size: 0,
+            modified: None,
+            etag: None,
+        }
+    }
+
+    /// Tells whether `entry` changed since it was recorded.
+    ///
+    /// Entity tags are trusted when both are known, modification times are
+    /// compared otherwise. Directories only change by becoming files.
+    pub fn has_changed(&self, entry: &Entry) -> bool {
+        if self.is_dir || entry.is_dir {
+            return self.is_dir != entry.is_dir;
+        }
+        if self.size != entry.size {
+            return true;
+        }
+        match (&self.etag, &entry.etag) {
+            (Some(recorded), Some(etag)) => recorded != etag,
+            _ => self.modified != entry.modified.map(seconds),
+        }
+    }
+}
+
+/// The paths that were in sync after the last run, with what each side had.
+#[derive(Debug, Default)]
+pub struct State {
+    file: PathBuf,
+    pub paths: BTreeMap<String, [Record; 2]>,
+}
+
+impl State {
+    /// Loads the state of the pair of locations, empty on the first run.
+    pub fn load(first: &str, second: &str) -> Result<State> {
+        State::read(state_dir()?.join(format!("{}.state", key(first, second)?)))
+    }
+
+    /// Reads the state file `file`, empty if it does not exist.
+    pub(crate) fn read(file: PathBuf) -> Result<State> {
+        let mut state = State {
+            file,
+            paths: BTreeMap::new(),
+        };
+        let content = match fs::read_to_string(&state.file) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(state),
+            Err(e) => return Err(e).at(&state.file),
+        };
+        let mut lines = content.lines();
+        if lines.next() != Some(HEADER) {
+            return Err(state.corrupt(1));
+        }
+        for (number, line) in lines.enumerate() {
+            let (path, records) = parse(line).ok_or_else(|| state.corrupt(number + 2))?;
+            state.paths.insert(path, records);
+        }
+        Ok(state)
+    }
+
+    /// Location of the state file.
+    pub fn file(&self) -> &Path {
+        &self.file
+    }
+
+    /// Writes the state file, replacing the previous one at once so that an
+    /// interrupted run never leaves a truncated state.
+    pub fn save(&self) -> Result<()> {
+        if let Some(dir) = self.file.parent() {
+            fs::create_dir_all(dir).at(dir)?;
+        }
+        let temporary = self.file.with_extension("state.tmp");
+        self.write(&temporary).at(&temporary)?;
+        fs::rename(&temporary, &self.file).at(&self.file)
+    }
+
+    fn write(&self, file: &Path) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(file)?);
+        writeln!(out, "{}", HEADER)?;
+        for (path, records) in &self.paths {
+            write!(out, "{}", encode(path))?;
+            for record in records {
+                let kind = if record.is_dir { "d" } else { "f" };
+                let modified = record.modified.map(|m| m.to_string()).unwrap_or_default();
+                let etag = record.etag.as_deref().map(encode).unwrap_or_default();
+                write!(out, "\t{}\t{}\t{}\t{}", kind, record.size, modified, etag)?;
+            }
+            writeln!(out)?;
+        }
+        out.into_inner().map_err(|e| e.into_error())?.sync_all()
+    }
+
+    fn corrupt(&self, line: usize) -> Error {
+        Error::Usage(format!(
+            "invalid state file '{}' at line {}, remove it to start over",
+            self.file.display(),
+            line
+        ))
+    }
+}
+
+/// Directory holding the state files.
+pub(crate) fn state_dir() -> Result<PathBuf> {
+    if let Some(dir) = env::var_os("XDG_STATE_HOME").filter(|dir| !dir.is_empty()) {
+        return Ok(PathBuf::from(dir).join("davsync"));
+    }
+    match env::var_os("HOME") {
+        Some(home) => Ok(PathBuf::from(home).join(".local/state/davsync")),
+        None => Err(Error::Usage(
+            "cannot locate the state directory: HOME is not set".to_owned(),
+        )),
+    }
+}
+
+/// Name of the state file of a pair of locations. Locations are hashed in
+/// their canonical spelling so that the same pair always gets the same state.
+fn key(first: &str, second: &str) -> Result<String> {
+    let canonical = |location: &str| Endpoint::parse(location)?.canonical();
+    let pair = format!("{}\n{}", canonical(first)?, canonical(second)?);
+    Ok(compare::sha1(&mut pair.as_bytes())?)
+}
+
+fn parse(line: &str) -> Option<(String, [Record; 2])> {
+    let mut fields = line.split('\t');
+    let path = decode(fields.next()?)?;
+    let mut record = || -> Option<Record> {
+        let is_dir = match fields.next()? {
+            "d" => true,
+            "f" => false,
+            _ => return None,
+        };
+        let size = fields.next()?.parse().ok()?;
+        let modified = match fields.next()? {
+            "" => None,
+            seconds => Some(seconds.parse().ok()?),
+        };
+        let etag = match fields.next()? {
+            "" => None,
+            etag => Some(decode(etag)?),
+        };
+        Some(Record {
+            is_dir,
+            size,
+            modified,
+            etag,
+        })
+    };
+    let records = [record()?, record()?];
+    match fields.next() {
+        None => Some((path, records)),
+        Some(_) => None,
+    }
+}
+
+fn encode(field: &str) -> String {
+    utf8_percent_encode(field, FIELD).to_string()
+}
+
+fn decode(field: &str) -> Option<String> {
+    percent_decode_str(field)
+        .decode_utf8()
+        .ok()
+        .map(|field| field.into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::*;
+
+    fn file(size: u64, modified: Option<u64>, etag: Option<&str>) -> Record {
+        Record {
+            is_dir: false,
+            size,
+            modified,
+            etag: etag.map(str::to_owned),
+        }
+    }
+
+    #[test]
+    fn lines_are_parsed() {
+        let (path, records) = parse("dir/a%09b\tf\t3\t1700000000\t%22x%22\tf\t3\t\t").unwrap();
+        assert_eq!(path, "dir/a\tb");
+        assert_eq!(
+            records,
+            [
+                file(3, Some(1700000000), Some("\"x\"")),
+                file(3, None, None)
+            ]
+        );
+        let (_, records) = parse("dir\td\t0\t\t\td\t0\t\t").unwrap();
+        assert_eq!(records, [Record::directory(), Record::directory()]);
+    }
+
+    #[test]
+    fn malformed_lines_are_rejected() {
+        for line in [
+            "",
+            "a\tf\t3\t1\t",
+            "a\tx\t3\t1\t\tf\t3\t1\t",
+            "a\tf\tthree\t1\t\tf\t3\t1\t",
+            "a\tf\t3\t1\t\tf\t3\t1\t\textra",
+        ] {
+            assert!(parse(line).is_none(), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn saved_states_read_back() {
+        let dir = env::temp_dir().join(format!("davsync-state-test-{}", std::process::id()));
+        let mut state = State::read(dir.join("pair.state")).unwrap();
+        assert!(state.paths.is_empty());
+        state
+            .paths
+            .insert("dir".to_owned(), [Record::directory(), Record::directory()]);
+        state.paths.insert(
+            "dir/50% off\n.txt".to_owned(),
+            [
+                file(12, Some(1700000000), Some("W/\"a\tb\"")),
+                file(12, None, None),
+            ],
+        );
+        state.save().unwrap();
+        let read = State::read(state.file().to_owned()).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(read.paths, state.paths);
+    }
+
+    #[test]
+    fn pairs_are_keyed_whatever_their_spelling() {
+        assert_eq!(
+            key("missing/dir", "https://alice@host/a%20b/").unwrap(),
+            key("./missing/dir/", "https://host/a b").unwrap()
+        );
+        assert_ne!(
+            key("missing/dir", "https://host/a").unwrap(),
+            key("https://host/a", "missing/dir").unwrap()
+        );
+    }
+
+    #[test]
+    fn changes_are_detected_by_etag_or_time() {
+        let entry = |size: u64, seconds: u64, etag: Option<&str>| Entry {
+            path: "a".to_owned(),
+            is_dir: false,
+            size,
+            modified: Some(UNIX_EPOCH + Duration::from_secs(seconds)),
+            etag: etag.map(str::to_owned),
+            checksum: None,
+        };
+        let record = file(3, Some(10), Some("e1"));
+        assert!(!record.has_changed(&entry(3, 99, Some("e1"))));
+        assert!(record.has_changed(&entry(3, 10, Some("e2"))));
+        assert!(record.has_changed(&entry(4, 10, Some("e1"))));
+        let record = file(3, Some(10), None);
+        assert!(!record.has_changed(&entry(3, 10, Some("e1"))));
+        assert!(record.has_changed(&entry(3, 11, None)));
+    }
+}
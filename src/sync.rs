@@ -0,0 +1,645 @@
+//! One-way synchronization from a source backend to a target backend.
+//!
+//! A synchronization first compares both sides to build a plan, the list of
+//! actions that make the target match the source, then executes it.
+
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use cli_toolbox::reportln;
+
+use crate::backend::{ancestors, Backend, Entry};
+use crate::bisync::Policy;
+use crate::compare;
+use crate::error::{Error, Result};
+use crate::filter::Filter;
+use crate::ignore::Ignores;
+use crate::pool;
+
+/// Settings of a synchronization.
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Modification times that differ by less than this are considered equal.
+    pub modify_window: Duration,
+    /// Compare files by content checksum instead of size and modification time.
+    pub checksum: bool,
+    /// Only report the plan, without changing anything.
+    pub dry_run: bool,
+    /// When to delete target entries that are not in the source, if at all.
+    pub delete: Option<DeleteTiming>,
+    /// Which entries take part in the synchronization.
+    pub filter: Filter,
+    /// Names of the per-directory ignore files read from the source tree,
+    /// the later ones overriding the earlier ones.
+    pub ignore_files: Vec<String>,
+    /// Also delete target entries that are excluded from the synchronization.
+    pub delete_excluded: bool,
+    /// Number of files transferred at once, and of collections listed at
+    /// once when servers refuse deep listings.
+    pub jobs: usize,
+    /// How two-way synchronizations resolve conflicts.
+    pub conflict: Policy,
+    /// Abort when more target entries than this would be deleted.
+    pub max_delete: Option<usize>,
+    /// Abort when more than this percentage of the target entries would be deleted.
+    pub max_delete_percent: Option<f64>,
+}
+
+/// When extraneous target entries are deleted, as with rsync's `--delete-*`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeleteTiming {
+    /// Before any transfer.
+    Before,
+    /// Along with the transfers, each directory being cleaned before its
+    /// content is synchronized.
+    During,
+    /// Once every transfer is done.
+    After,
+}
+
+/// Counters reported at the end of a synchronization.
+#[derive(Debug, Default)]
+pub struct Stats {
+    pub directories: usize,
+    pub transferred: usize,
+    pub unchanged: usize,
+    pub deleted: usize,
+    /// Files changed on both sides of a two-way synchronization.
+    pub conflicts: usize,
+}
+
+/// One step of a synchronization plan.
+#[derive(Debug, Clone)]
+pub enum Action {
+    /// Create the directory at this path on the target.
+    Mkdir(String),
+    /// Copy this source file to the target.
+    Transfer(Entry),
+    /// Leave this file alone, the target copy is up to date.
+    Skip(Entry),
+    /// Delete this target file or directory, with all its content.
+    Delete(Entry),
+}
+
+/// The actions of a synchronization, with what is needed to judge them.
+#[derive(Debug, Default)]
+pub struct Plan {
+    pub actions: Vec<Action>,
+    /// Number of entries found on the target, directories included.
+    pub target_entries: usize,
+    /// Number of target entries the deletions remove, the content of deleted
+    /// directories included.
+    pub removed_entries: usize,
+}
+
+impl Action {
+    /// Path the action applies to.
+    pub fn path(&self) -> &str {
+        match self {
+            Action::Mkdir(path) => path,
+            Action::Transfer(entry) | Action::Skip(entry) | Action::Delete(entry) => &entry.path,
+        }
+    }
+}
+
+/// Mirrors the `source` file or directory to `target`.
+///
+/// In dry-run mode, the plan is reported and nothing is changed.
+pub fn run(source: &dyn Backend, target: &dyn Backend, options: &Options) -> Result<Stats> {
+    let sync = Sync {
+        source,
+        target,
+        options,
+    };
+    let plan = sync.plan()?;
+    check_deletions(target, &plan, options)?;
+    if options.dry_run {
+        for action in &plan.actions {
+            sync.report(action);
+        }
+        Ok(count(&plan.actions))
+    } else {
+        let stats = sync.execute(&plan.actions)?;
+        target.clean_up();
+        Ok(stats)
+    }
+}
+
+/// Counts what a plan does.
+fn count(plan: &[Action]) -> Stats {
+    let mut stats = Stats::default();
+    for action in plan {
+        match action {
+            Action::Mkdir(_) => stats.directories += 1,
+            Action::Transfer(_) => stats.transferred += 1,
+            Action::Skip(_) => stats.unchanged += 1,
+            Action::Delete(_) => stats.deleted += 1,
+        }
+    }
+    stats
+}
+
+/// Aborts when the plan deletes more entries of `target` than the
+/// `--max-delete` limits allow, reporting the deletions it would have made.
+pub(crate) fn check_deletions(target: &dyn Backend, plan: &Plan, options: &Options) -> Result<()> {
+    let removed = plan.removed_entries;
+    let mut exceeded = None;
+    if let Some(max) = options.max_delete.filter(|&max| removed > max) {
+        exceeded = Some(format!("--max-delete {}", max));
+    }
+    if let Some(max) = options.max_delete_percent {
+        let percent = 100.0 * removed as f64 / plan.target_entries.max(1) as f64;
+        if percent > max {
+            exceeded = Some(format!("--max-delete-percent {}", max));
+        }
+    }
+    let limit = match exceeded {
+        Some(limit) => limit,
+        None => return Ok(()),
+    };
+    for action in &plan.actions {
+        if let Action::Delete(entry) = action {
+            eprintln!("would delete '{}'", target.location(&entry.path));
+        }
+    }
+    Err(Error::Aborted(format!(
+        "{} of the {} entries of '{}' would be deleted, more than {} allows",
+        removed,
+        plan.target_entries,
+        target.location(""),
+        limit
+    )))
+}
+
+/// Sort key of the actions with `--delete`: like rsync, the content of each
+/// directory comes together, deletions first. Directories still come before
+/// their content, since the path of a directory sorts before the ones of its
+/// subdirectories.
+fn during_order(action: &Action) -> (&str, bool, &str) {
+    let path = action.path();
+    let directory = path.rsplit_once('/').map_or("", |(directory, _)| directory);
+    (directory, !matches!(action, Action::Delete(_)), path)
+}
+
+struct Sync<'a> {
+    source: &'a dyn Backend,
+    target: &'a dyn Backend,
+    options: &'a Options,
+}
+
+impl Sync<'_> {
+    /// Compares both sides and returns the actions to perform, every
+    /// directory coming before its content.
+    fn plan(&self) -> Result<Plan> {
+        let root = self.source.stat("")?.ok_or_else(|| {
+            Error::Usage(format!("'{}' does not exist", self.source.location("")))
+        })?;
+        let existing = self.target.stat("")?;
+        let mut plan = Plan::default();
+
+        if root.is_dir {
+            let exists = match existing {
+                Some(entry) if entry.is_dir => true,
+                Some(_) => return Err(self.mismatch(&root)),
+                None => {
+                    plan.actions.push(Action::Mkdir(String::new()));
+                    false
+                }
+            };
+            self.plan_tree(exists, &mut plan)?;
+        } else {
+            plan.target_entries = existing.is_some() as usize;
+            plan.actions.push(self.plan_file(root, existing.as_ref())?);
+        }
+        Ok(plan)
+    }
+
+    /// Plans the content of the source directory.
+    ///
+    /// `exists` tells whether the target directory was already there, in which
+    /// case its whole tree is listed to find what can be skipped.
+    ///
+    /// Excluded target entries are left out, and thus never deleted, unless
+    /// `delete_excluded` is set.
+    fn plan_tree(&self, exists: bool, plan: &mut Plan) -> Result<()> {
+        let filter = &self.options.filter;
+        // Command line rules take precedence over the ignore files.
+        let decided = |entry: &Entry| filter.matches(&entry.path, entry.is_dir).is_some();
+        // Sorting puts every directory before its content.
+        let mut entries = self.source.walk("", filter)?;
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        let ignores = Ignores::load(
+            self.source,
+            &self.options.ignore_files,
+            &mut entries,
+            &decided,
+        )?;
+
+        let existing: HashMap<String, Entry> = if exists {
+            let entries = if self.options.delete_excluded {
+                self.target.walk("", &Filter::default())?
+            } else {
+                let mut entries = self.target.walk("", filter)?;
+                entries.sort_by(|a, b| a.path.cmp(&b.path));
+                ignores.prune(&mut entries, &decided);
+                entries
+            };
+            entries
+                .into_iter()
+                .map(|entry| (entry.path.clone(), entry))
+                .collect()
+        } else {
+            HashMap::new()
+        };
+        plan.target_entries = existing.len();
+
+        // Target entries replaced by a source entry of the other kind.
+        let mut replaced = HashSet::new();
+        for entry in &entries {
+            let mut target_entry = existing.get(&entry.path);
+            if let Some(existing_entry) = target_entry.filter(|e| e.is_dir != entry.is_dir) {
+                if self.options.delete.is_none() {
+                    return Err(self.mismatch(entry));
+                }
+                plan.actions.push(Action::Delete(existing_entry.clone()));
+                replaced.insert(existing_entry.path.as_str());
+                target_entry = None;
+            }
+            if entry.is_dir {
+                if target_entry.is_none() {
+                    plan.actions.push(Action::Mkdir(entry.path.clone()));
+                }
+            } else {
+                plan.actions
+                    .push(self.plan_file(entry.clone(), target_entry)?);
+            }
+        }
+
+        let timing = match self.options.delete {
+            Some(timing) => timing,
+            None => return Ok(()),
+        };
+        let sources: HashSet<&str> = entries.iter().map(|e| e.path.as_str()).collect();
+        let mut extraneous: Vec<&Entry> = existing
+            .values()
+            .filter(|e| !sources.contains(e.path.as_str()))
+            .collect();
+        extraneous.sort_by(|a, b| a.path.cmp(&b.path));
+        plan.removed_entries = replaced.len() + extraneous.len();
+        // Deleting a directory deletes its content too.
+        let mut deleted = replaced;
+        let mut deletions = Vec::new();
+        for entry in extraneous {
+            if ancestors(&entry.path).any(|dir| deleted.contains(dir)) {
+                continue;
+            }
+            if entry.is_dir {
+                deleted.insert(&entry.path);
+            }
+            deletions.push(Action::Delete(entry.clone()));
+        }
+
+        match timing {
+            DeleteTiming::Before => {
+                deletions.append(&mut plan.actions);
+                plan.actions = deletions;
+            }
+            DeleteTiming::During => {
+                plan.actions.append(&mut deletions);
+                plan.actions
+                    .sort_by(|a, b| during_order(a).cmp(&during_order(b)));
+            }
+            DeleteTiming::After => plan.actions.append(&mut deletions),
+        }
+        Ok(())
+    }
+
+    /// Decides whether the file `entry` has to be transferred.
+    fn plan_file(&self, mut entry: Entry, target_entry: Option<&Entry>) -> Result<Action> {
+        if let Some(target_entry) = target_entry {
+            if target_entry.is_dir {
+                return Err(self.mismatch(&entry));
+            }
+            if self.is_up_to_date(&mut entry, target_entry)? {
+                return Ok(Action::Skip(entry));
+            }
+        }
+        Ok(Action::Transfer(entry))
+    }
+
+    /// Compares a source file with its target copy.
+    ///
+    /// In checksum mode, the checksum computed for the source is kept in
+    /// `entry` so that it can be sent along with the upload.
+    fn is_up_to_date(&self, entry: &mut Entry, target_entry: &Entry) -> Result<bool> {
+        if !self.options.checksum {
+            return Ok(compare::quick_check(
+                entry,
+                target_entry,
+                self.options.modify_window,
+            ));
+        }
+        if entry.size != target_entry.size {
+            return Ok(false);
+        }
+        let checksum = self.source.checksum(entry)?;
+        let up_to_date = checksum == self.target.checksum(target_entry)?;
+        entry.checksum = Some(checksum);
+        Ok(up_to_date)
+    }
+
+    /// Performs the actions, transferring up to `jobs` files at once.
+    ///
+    /// Directories are created and entries deleted in the order of the plan,
+    /// waiting for the transfers planned before a deletion. Actions are
+    /// reported in that order too.
+    fn execute(&self, plan: &[Action]) -> Result<Stats> {
+        let mut stats = Stats::default();
+        let work = |entry: &Entry| transfer(self.source, self.target, entry);
+        pool::scoped(self.options.jobs, work, |pool| {
+            for action in plan {
+                for result in pool.finished() {
+                    result?;
+                    stats.transferred += 1;
+                }
+                if let Action::Delete(_) = action {
+                    while let Some(result) = pool.wait() {
+                        result?;
+                        stats.transferred += 1;
+                    }
+                }
+                self.report(action);
+                match action {
+                    Action::Mkdir(path) => {
+                        self.target.mkdir(path)?;
+                        stats.directories += 1;
+                    }
+                    Action::Transfer(entry) => pool.submit(entry),
+                    Action::Skip(_) => stats.unchanged += 1,
+                    Action::Delete(entry) => {
+                        self.target.delete(&entry.path)?;
+                        stats.deleted += 1;
+                    }
+                }
+            }
+            while let Some(result) = pool.wait() {
+                result?;
+                stats.transferred += 1;
+            }
+            Ok(stats)
+        })
+    }
+
+    /// Reports an action, at terse level in dry-run mode and at verbose
+    /// level otherwise. Skipped files are only reported at verbose level.
+    fn report(&self, action: &Action) {
+        let (verb, location) = match action {
+            Action::Mkdir(path) => ("mkdir", self.target.location(path)),
+            Action::Transfer(entry) => (
+                transfer_verb(self.source, self.target),
+                self.source.location(&entry.path),
+            ),
+            Action::Skip(entry) => ("skip", self.source.location(&entry.path)),
+            Action::Delete(entry) => ("delete", self.target.location(&entry.path)),
+        };
+        match action {
+            Action::Skip(_) => reportln!(@verbose "{:<8} '{}'", verb, location),
+            _ if self.options.dry_run => reportln!(@terse "{:<8} '{}'", verb, location),
+            _ => reportln!(@verbose "{:<8} '{}'", verb, location),
+        }
+    }
+
+    fn mismatch(&self, entry: &Entry) -> Error {
+        let (is, is_not) = if entry.is_dir {
+            ("a directory", "is not")
+        } else {
+            ("a file", "is a directory")
+        };
+        Error::Usage(format!(
+            "'{}' is {} but '{}' {}",
+            self.source.location(&entry.path),
+            is,
+            self.target.location(&entry.path),
+            is_not
+        ))
+    }
+}
+
+/// Copies the file `entry` from `source` to `target`, letting the target
+/// backend copy it itself when it can, and continuing where an interrupted
+/// copy stopped when the target kept it.
+pub(crate) fn transfer(source: &dyn Backend, target: &dyn Backend, entry: &Entry) -> Result<()> {
+    if target.copy_from(source, &entry.path)? {
+        return Ok(());
+    }
+    match target.partial(entry)? {
+        Some(partial) => {
+            let (offset, data) = source.read_from(entry, partial.len, &partial.etag)?;
+            target.resume(entry, offset, data)
+        }
+        None => target.write(entry, source.read(&entry.path)?),
+    }
+}
+
+/// Names a transfer from `source` to `target` in reports.
+pub(crate) fn transfer_verb(source: &dyn Backend, target: &dyn Backend) -> &'static str {
+    match (source.remote_url(""), target.remote_url("")) {
+        (None, Some(_)) => "upload",
+        (Some(_), None) => "download",
+        _ => "copy",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::MemoryBackend;
+
+    fn plan(source: &MemoryBackend, target: &MemoryBackend, options: &Options) -> Vec<String> {
+        let sync = Sync {
+            source,
+            target,
+            options,
+        };
+        let plan = sync.plan().unwrap();
+        plan.actions
+            .iter()
+            .map(|action| {
+                let verb = match action {
+                    Action::Mkdir(_) => "mkdir",
+                    Action::Transfer(_) => "transfer",
+                    Action::Skip(_) => "skip",
+                    Action::Delete(_) => "delete",
+                };
+                format!("{} {}", verb, action.path())
+            })
+            .collect()
+    }
+
+    fn deleting(timing: DeleteTiming) -> Options {
+        Options {
+            delete: Some(timing),
+            jobs: 1,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn only_new_and_changed_files_are_transferred() {
+        let source = MemoryBackend::new()
+            .file("same", "a", 10)
+            .file("resized", "abc", 10)
+            .file("touched", "a", 20)
+            .file("dir/new", "a", 10);
+        let target = MemoryBackend::new()
+            .file("same", "a", 10)
+            .file("resized", "ab", 10)
+            .file("touched", "a", 10)
+            .file("extra", "a", 10);
+        let options = Options {
+            jobs: 1,
+            ..Default::default()
+        };
+        assert_eq!(
+            plan(&source, &target, &options),
+            [
+                "mkdir dir",
+                "transfer dir/new",
+                "transfer resized",
+                "skip same",
+                "transfer touched"
+            ]
+        );
+    }
+
+    #[test]
+    fn a_missing_target_is_created() {
+        let source = MemoryBackend::new().file("a", "a", 10);
+        let target = MemoryBackend::new();
+        target.delete("").unwrap();
+        let options = Options {
+            jobs: 1,
+            ..Default::default()
+        };
+        assert_eq!(plan(&source, &target, &options), ["mkdir ", "transfer a"]);
+    }
+
+    #[test]
+    fn deletions_are_planned_when_asked() {
+        let source = MemoryBackend::new()
+            .file("a/new", "a", 10)
+            .file("b/new", "a", 10);
+        let target = MemoryBackend::new()
+            .file("a/old", "a", 10)
+            .file("b/old", "a", 10)
+            .file("old/file", "a", 10);
+        assert_eq!(
+            plan(&source, &target, &deleting(DeleteTiming::Before)),
+            [
+                "delete a/old",
+                "delete b/old",
+                "delete old",
+                "transfer a/new",
+                "transfer b/new"
+            ]
+        );
+        // Each directory is cleaned before its content is transferred.
+        assert_eq!(
+            plan(&source, &target, &deleting(DeleteTiming::During)),
+            [
+                "delete old",
+                "delete a/old",
+                "transfer a/new",
+                "delete b/old",
+                "transfer b/new"
+            ]
+        );
+        assert_eq!(
+            plan(&source, &target, &deleting(DeleteTiming::After)),
+            [
+                "transfer a/new",
+                "transfer b/new",
+                "delete a/old",
+                "delete b/old",
+                "delete old"
+            ]
+        );
+    }
+
+    #[test]
+    fn entries_of_another_kind_are_replaced_with_delete() {
+        let source = MemoryBackend::new().file("entry/file", "a", 10);
+        let target = MemoryBackend::new().file("entry", "a", 10);
+        let options = Options {
+            jobs: 1,
+            ..Default::default()
+        };
+        assert!(Sync {
+            source: &source,
+            target: &target,
+            options: &options,
+        }
+        .plan()
+        .is_err());
+        assert_eq!(
+            plan(&source, &target, &deleting(DeleteTiming::During)),
+            ["delete entry", "mkdir entry", "transfer entry/file"]
+        );
+    }
+
+    #[test]
+    fn excluded_entries_are_left_alone() {
+        let source = MemoryBackend::new()
+            .file("keep", "a", 10)
+            .file("skip.tmp", "a", 10);
+        let target = MemoryBackend::new().file("old.tmp", "a", 10);
+        let mut options = deleting(DeleteTiming::During);
+        options.filter.add(false, "*.tmp").unwrap();
+        assert_eq!(plan(&source, &target, &options), ["transfer keep"]);
+        options.delete_excluded = true;
+        assert_eq!(
+            plan(&source, &target, &options),
+            ["delete old.tmp", "transfer keep"]
+        );
+    }
+
+    #[test]
+    fn deletion_limits_abort_before_changing_anything() {
+        let source = MemoryBackend::new().file("a", "a", 10);
+        let target = MemoryBackend::new()
+            .file("a", "a", 10)
+            .file("old/1", "a", 10)
+            .file("old/2", "a", 10);
+        let mut options = deleting(DeleteTiming::During);
+        options.max_delete = Some(2);
+        assert!(matches!(
+            run(&source, &target, &options),
+            Err(Error::Aborted(_))
+        ));
+        assert_eq!(target.paths(), ["a", "old", "old/1", "old/2"]);
+        options.max_delete = Some(3);
+        assert_eq!(run(&source, &target, &options).unwrap().deleted, 1);
+        assert_eq!(target.paths(), ["a"]);
+    }
+
+    #[test]
+    fn run_mirrors_the_source() {
+        let source = MemoryBackend::new()
+            .file("a", "new", 20)
+            .file("dir/b", "b", 10)
+            .dir("empty");
+        let target = MemoryBackend::new()
+            .file("a", "old", 10)
+            .file("stale", "a", 10);
+        let stats = run(&source, &target, &deleting(DeleteTiming::During)).unwrap();
+        assert_eq!(
+            (stats.transferred, stats.directories, stats.deleted),
+            (2, 2, 1)
+        );
+        assert_eq!(target.paths(), ["a", "dir", "dir/b", "empty"]);
+        assert_eq!(target.content("a").as_deref(), Some("new"));
+
+        let stats = run(&source, &target, &deleting(DeleteTiming::During)).unwrap();
+        assert_eq!((stats.transferred, stats.unchanged), (0, 2));
+    }
+}